@@ -1,13 +1,157 @@
-use fxhash::FxHashMap;
 use glam::IVec3;
-use rube::tree::{Node, VoxelTree, generate_tree};
+use rube::format::{Compression, Header, SourceMetadata};
+use rube::page::page_depth;
+use rube::patch::Patch;
+use rube::transform::Axis;
+use rube::tree::{Leaves, MAX_EXP, MIN_EXP, Node, NodeHash, VoxelTree, par_generate_tree};
 
+mod mesh;
 mod obj;
 mod vox;
 
+/// Converts every `.vox` and `.obj` file in `assets/` into a tree file, exports
+/// a tree back into a MagicaVoxel scene or a polygon mesh, re-encodes a tree, diffs
+/// and patches trees, or checks a tree for corruption.
+///
+/// ```text
+/// rube-voxelize [--exp <n>] [--compression <codec>] [--page-exp <n>] [<transform>...]
+///     --exp <n>              Tree depth (2^n voxels per axis), defaults to the smallest
+///                            that fits.
+///     --compression <codec>  One of `bzip2` (the default), `zstd`, `lz4`, `none` or
+///                            `mapped`, which also picks the file extension.
+///     --page-exp <n>         Splits the tree into pages of 2^n voxels per axis that are
+///                            loaded around the camera, see `rube::page`.
+///     --crop <x,y,z> <x,y,z> Keeps only the voxels in `min..max`.
+///     --mirror <axis>        Mirrors the model along `x`, `y` or `z`.
+///     --rotate <axis> <n>    Rotates the model by n quarter turns around `x`, `y` or `z`.
+///     --translate <x,y,z>    Moves the model by a number of voxels.
+///     Transforms are applied in order, each in the coordinates left by the previous
+///     one, and the model is kept within positive coordinates.
+/// rube-voxelize export <tree.bin.bz2> <out.vox>
+/// rube-voxelize mesh <tree.bin.bz2> <out.obj|out.ply>
+///     Writes the visible faces as greedily merged quads in voxel units, colored by
+///     material. OBJ files get a `.mtl` file next to them.
+/// rube-voxelize convert <tree.bin.bz2> <out.bin> [--compression <codec>] [--page-exp <n>]
+///     Re-encodes a tree, `mapped` (the default) writes a tree that `VoxelTree::open`
+///     memory maps instead of decoding.
+/// rube-voxelize diff <source.bin.bz2> <target.bin.bz2> <out.patch> [--compression <codec>]
+///     Writes the changes from source to target, compressed with bzip2 by default.
+/// rube-voxelize patch <source.bin.bz2> <in.patch> <out.bin.bz2> [--compression <codec>]
+///     Applies a patch written by `diff`, the output is compressed like the source.
+/// rube-voxelize inspect <tree.bin.bz2>
+///     Validates the tree and prints its header and statistics, alias `validate`.
+/// ```
 fn main() -> std::io::Result<()> {
+    let mut args = std::env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("export") => {
+            let (Some(input), Some(output), None) = (args.nth(1), args.next(), args.next()) else {
+                panic!("usage: rube-voxelize export <tree.bin.bz2> <out.vox>");
+            };
+            let tree =
+                VoxelTree::decompress(&std::fs::read(&input)?).map_err(std::io::Error::other)?;
+            return vox::export(&tree.to_map(), output);
+        }
+        Some("mesh") => {
+            let (Some(input), Some(output), None) = (args.nth(1), args.next(), args.next()) else {
+                panic!("usage: rube-voxelize mesh <tree.bin.bz2> <out.obj|out.ply>");
+            };
+            let tree =
+                VoxelTree::decompress(&std::fs::read(&input)?).map_err(std::io::Error::other)?;
+            return mesh::export(&tree, output);
+        }
+        Some("convert") => {
+            let usage = "usage: rube-voxelize convert <tree.bin.bz2> <out.bin> \
+                         [--compression <codec>] [--page-exp <n>]";
+            let (Some(input), Some(output)) = (args.nth(1), args.next()) else {
+                panic!("{usage}");
+            };
+            let mut compression = Compression::Mapped;
+            let mut page_exp = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--compression" => compression = parse_compression(args.next()),
+                    "--page-exp" => page_exp = Some(parse_page_exp(args.next())),
+                    _ => panic!("{usage}"),
+                }
+            }
+            return convert(&input, &output, compression, page_exp);
+        }
+        Some("diff") => {
+            let usage = "usage: rube-voxelize diff <source.bin.bz2> <target.bin.bz2> \
+                         <out.patch> [--compression <codec>]";
+            let (Some(source), Some(target), Some(output)) =
+                (args.nth(1), args.next(), args.next())
+            else {
+                panic!("{usage}");
+            };
+            let compression = match (args.next().as_deref(), args.next()) {
+                (None, _) => Compression::Bzip2,
+                (Some("--compression"), codec) => parse_compression(codec),
+                _ => panic!("{usage}"),
+            };
+            return diff(&source, &target, &output, compression);
+        }
+        Some("patch") => {
+            let usage = "usage: rube-voxelize patch <source.bin.bz2> <in.patch> <out.bin.bz2> \
+                         [--compression <codec>]";
+            let (Some(source), Some(patch), Some(output)) = (args.nth(1), args.next(), args.next())
+            else {
+                panic!("{usage}");
+            };
+            let compression = match (args.next().as_deref(), args.next()) {
+                (None, _) => None,
+                (Some("--compression"), codec) => Some(parse_compression(codec)),
+                _ => panic!("{usage}"),
+            };
+            return apply_patch(&source, &patch, &output, compression);
+        }
+        Some("inspect" | "validate") => {
+            let (Some(input), None) = (args.nth(1), args.next()) else {
+                panic!("usage: rube-voxelize inspect <tree.bin.bz2>");
+            };
+            return inspect(&input);
+        }
+        _ => {}
+    }
+
+    let mut fixed_exp = None;
+    let mut compression = Compression::Bzip2;
+    let mut page_exp = None;
+    let mut transforms = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--exp" => {
+                let exp = args
+                    .next()
+                    .and_then(|exp| exp.parse::<u32>().ok())
+                    .filter(|exp| exp.is_multiple_of(2) && (MIN_EXP..=MAX_EXP).contains(exp))
+                    .unwrap_or_else(|| {
+                        panic!("`--exp` expects an even integer in {MIN_EXP}..={MAX_EXP}")
+                    });
+                fixed_exp = Some(exp);
+            }
+            "--compression" => compression = parse_compression(args.next()),
+            "--page-exp" => page_exp = Some(parse_page_exp(args.next())),
+            "--crop" => {
+                let (min, max) = (parse_ivec3(args.next()), parse_ivec3(args.next()));
+                transforms.push(Transform::Crop(min, max));
+            }
+            "--mirror" => transforms.push(Transform::Mirror(parse_axis(args.next()))),
+            "--rotate" => {
+                let axis = parse_axis(args.next());
+                let quarter_turns = args
+                    .next()
+                    .and_then(|turns| turns.parse().ok())
+                    .unwrap_or_else(|| panic!("`--rotate` expects a number of quarter turns"));
+                transforms.push(Transform::Rotate(axis, quarter_turns));
+            }
+            "--translate" => transforms.push(Transform::Translate(parse_ivec3(args.next()))),
+            _ => panic!("unknown argument `{arg}`"),
+        }
+    }
+
     for entry in std::fs::read_dir("assets")? {
-        let exp = 12;
         let mut path = entry?.path();
         if let Some(mut map) = match path.extension().map(|e| e.to_string_lossy()).as_deref() {
             Some("vox") => Some(vox::voxelize(&path)),
@@ -15,13 +159,44 @@ fn main() -> std::io::Result<()> {
             _ => None,
         } {
             map.shift_to_positive();
-            println!("Treeifying {}...", path.display());
+            for transform in &transforms {
+                match *transform {
+                    Transform::Crop(min, max) => map.crop(min, max),
+                    Transform::Mirror(axis) => map.mirror(axis),
+                    Transform::Rotate(axis, quarter_turns) => map.rotate(axis, quarter_turns),
+                    Transform::Translate(offset) => map.translate(offset),
+                }
+                map.shift_to_positive();
+            }
+            let exp = match fixed_exp {
+                Some(exp) if exp < map.tree_exp() => {
+                    println!(
+                        "[ERROR] {} needs a depth of at least 2^{}, skipping",
+                        path.display(),
+                        map.tree_exp()
+                    );
+                    continue;
+                }
+                Some(exp) => exp,
+                None => map.tree_exp(),
+            };
+            if let Some(page_exp) = page_exp
+                && page_depth(exp, page_exp).is_none()
+            {
+                println!(
+                    "[ERROR] {} can't be split into pages of 2^{page_exp} at a depth of 2^{exp}, \
+                     skipping",
+                    path.display()
+                );
+                continue;
+            }
+            println!("Treeifying {} @ 2^{exp}...", path.display());
             let start = std::time::Instant::now();
             let mut nodes = vec![Node::default()];
-            let mut leaves = Vec::new();
-            let mut node_hash = FxHashMap::default();
+            let mut leaves = Leaves::for_materials(map.materials.len());
+            let mut node_hash = NodeHash::default();
             let mut saved_bytes = 0;
-            let node = generate_tree(
+            let node = par_generate_tree(
                 &map,
                 &mut node_hash,
                 &mut nodes,
@@ -31,37 +206,205 @@ fn main() -> std::io::Result<()> {
                 &mut saved_bytes,
             );
             nodes[0] = node;
-            let palette = map.palette.to_vec();
-            let tree = VoxelTree {
-                nodes,
-                leaves,
-                palette,
-                exp,
-            };
-            let bytes = tree.compress();
+            let materials = map.materials.clone();
+            let tree = VoxelTree::new(nodes.into(), leaves, materials, exp);
+            let bytes = encode(
+                &tree,
+                compression,
+                SourceMetadata {
+                    path: path.file_name().unwrap().to_string_lossy().to_string(),
+                    generator: concat!("rube-voxelize ", env!("CARGO_PKG_VERSION")).to_string(),
+                },
+                page_exp,
+            );
             let file_stem = path.file_stem().unwrap().to_string_lossy().to_string();
             path.pop();
-            std::fs::write(path.join(format!("{}.bin.bz2", file_stem)), bytes)?;
+            std::fs::write(
+                path.join(format!("{file_stem}.{}", compression.extension())),
+                bytes,
+            )?;
             println!("  [{:?}]", start.elapsed());
 
-            println!("  Voxels: {}", tree.leaves.len());
-            println!(
-                "  Node tree: {:.2} MB",
-                std::mem::size_of_val(tree.nodes.as_slice()) as f32 / 1024.0 / 1024.0
-            );
-            println!(
-                "  Leaves: {:.2} MB",
-                std::mem::size_of_val(tree.leaves.as_slice()) as f32 / 1024.0 / 1024.0
-            );
+            print_stats(&tree);
+            println!("Saved: {:.2} MB", saved_bytes as f32 / 1024.0 / 1024.0);
+        }
+    }
+    Ok(())
+}
+
+// Applied to imported models in command line order.
+enum Transform {
+    Crop(IVec3, IVec3),
+    Mirror(Axis),
+    Rotate(Axis, i32),
+    Translate(IVec3),
+}
+
+fn parse_axis(axis: Option<String>) -> Axis {
+    match axis.as_deref() {
+        Some("x") => Axis::X,
+        Some("y") => Axis::Y,
+        Some("z") => Axis::Z,
+        _ => panic!("expected an axis, one of x, y or z"),
+    }
+}
+
+fn parse_ivec3(vector: Option<String>) -> IVec3 {
+    vector
+        .and_then(|vector| {
+            let components = vector
+                .split(',')
+                .map(|c| c.trim().parse().ok())
+                .collect::<Option<Vec<i32>>>()?;
+            <[i32; 3]>::try_from(components).ok()
+        })
+        .map(IVec3::from_array)
+        .unwrap_or_else(|| panic!("expected a vector of three integers, like `8,0,-4`"))
+}
+
+fn parse_compression(codec: Option<String>) -> Compression {
+    match codec.as_deref() {
+        Some("bzip2") => Compression::Bzip2,
+        Some("zstd") => Compression::Zstd,
+        Some("lz4") => Compression::Lz4,
+        Some("none") => Compression::None,
+        Some("mapped") => Compression::Mapped,
+        _ => panic!("`--compression` expects one of bzip2, zstd, lz4, none or mapped"),
+    }
+}
+
+fn parse_page_exp(page_exp: Option<String>) -> u32 {
+    page_exp
+        .and_then(|page_exp| page_exp.parse().ok())
+        .unwrap_or_else(|| panic!("`--page-exp` expects an integer"))
+}
+
+fn encode(
+    tree: &VoxelTree,
+    compression: Compression,
+    source: SourceMetadata,
+    page_exp: Option<u32>,
+) -> Vec<u8> {
+    match page_exp {
+        Some(page_exp) => rube::page::encode(tree, compression, source, page_exp),
+        None => rube::format::encode(tree, compression, source),
+    }
+}
+
+fn convert(
+    input: &str,
+    output: &str,
+    compression: Compression,
+    page_exp: Option<u32>,
+) -> std::io::Result<()> {
+    let bytes = std::fs::read(input)?;
+    let tree = VoxelTree::decompress(&bytes).map_err(std::io::Error::other)?;
+    if let Some(page_exp) = page_exp
+        && page_depth(tree.exp, page_exp).is_none()
+    {
+        return Err(std::io::Error::other(format!(
+            "pages of 2^{page_exp} voxels need an even exponent in 4..{}",
+            tree.exp
+        )));
+    }
+    // Legacy files have no header to take the source from.
+    let source = Header::read(&bytes)
+        .map_err(std::io::Error::other)?
+        .map(|(header, _)| header.source)
+        .unwrap_or_else(|| SourceMetadata {
+            path: input.to_string(),
+            generator: concat!("rube-voxelize ", env!("CARGO_PKG_VERSION")).to_string(),
+        });
+    println!("Converting {input} to {compression:?}...");
+    std::fs::write(output, encode(&tree, compression, source, page_exp))
+}
+
+fn diff(source: &str, target: &str, output: &str, compression: Compression) -> std::io::Result<()> {
+    let load = |path| VoxelTree::decompress(&std::fs::read(path)?).map_err(std::io::Error::other);
+    let (source_tree, target_tree) = (load(source)?, load(target)?);
+    println!("Diffing {source} against {target}...");
+    let start = std::time::Instant::now();
+    let patch = Patch::diff(&source_tree, &target_tree);
+    let bytes = patch.encode(compression);
+    println!("  Changes: {}", patch.changes.len());
+    println!("  Size: {:.2} KB", bytes.len() as f32 / 1024.0);
+    println!("  [{:?}]", start.elapsed());
+    std::fs::write(output, bytes)
+}
+
+fn apply_patch(
+    source: &str,
+    patch: &str,
+    output: &str,
+    compression: Option<Compression>,
+) -> std::io::Result<()> {
+    let bytes = std::fs::read(source)?;
+    let mut tree = VoxelTree::decompress(&bytes).map_err(std::io::Error::other)?;
+    let patch = Patch::decode(&std::fs::read(patch)?).map_err(std::io::Error::other)?;
+    println!("Patching {source}...");
+    tree.apply_patch(&patch).map_err(std::io::Error::other)?;
+    let header = Header::read(&bytes).map_err(std::io::Error::other)?;
+    let compression = compression
+        .or(header.as_ref().map(|(header, _)| header.compression))
+        .unwrap_or(Compression::Bzip2);
+    let source = header
+        .map(|(header, _)| header.source)
+        .unwrap_or_else(|| SourceMetadata {
+            path: source.to_string(),
+            generator: concat!("rube-voxelize ", env!("CARGO_PKG_VERSION")).to_string(),
+        });
+    std::fs::write(output, rube::format::encode(&tree, compression, source))
+}
+
+fn inspect(path: &str) -> std::io::Result<()> {
+    let bytes = std::fs::read(path)?;
+    let tree = VoxelTree::decompress(&bytes).map_err(std::io::Error::other)?;
+    println!("Inspecting {path}...");
+    match Header::read(&bytes).map_err(std::io::Error::other)? {
+        Some((header, _)) => {
+            println!("  Version: {}", header.version);
+            println!("  Compression: {:?}", header.compression);
+            println!("  Paged: {}", header.paged);
             println!(
-                "  Total: {:.2} MB",
-                (std::mem::size_of_val(tree.nodes.as_slice())
-                    + std::mem::size_of_val(tree.leaves.as_slice())) as f32
-                    / 1024.0
-                    / 1024.0
+                "  Material ids: {} bit",
+                if header.wide_leaves { 16 } else { 8 }
             );
-            println!("Saved: {:.2} MB", saved_bytes as f32 / 1024.0 / 1024.0);
+            println!("  Source: {}", header.source.path);
+            println!("  Generator: {}", header.source.generator);
         }
+        None => println!("  Version: 0 (no header)"),
     }
+    println!("  Depth: 2^{}", tree.exp);
+    tree.validate().map_err(std::io::Error::other)?;
+    print_stats(&tree);
+    println!("  Valid");
     Ok(())
 }
+
+fn print_stats(tree: &VoxelTree) {
+    const MB: f32 = 1024.0 * 1024.0;
+    let stats = tree.stats();
+    println!("  Voxels: {}", stats.voxels);
+    for (depth, level) in stats.levels.iter().enumerate() {
+        println!(
+            "  Level {depth}: {} nodes, {:.1}% filled",
+            level.nodes,
+            level.fill_ratio() * 100.0
+        );
+    }
+    println!("  Node tree: {:.2} MB", stats.node_bytes as f32 / MB);
+    println!("  Leaves: {:.2} MB", stats.leaf_bytes as f32 / MB);
+    println!("  Materials: {:.2} MB", stats.material_bytes as f32 / MB);
+    if stats.unreachable_bytes > 0 {
+        println!(
+            "  Unreachable: {:.2} MB",
+            stats.unreachable_bytes as f32 / MB
+        );
+    }
+    println!(
+        "  Total: {:.2} MB",
+        (stats.node_bytes + stats.leaf_bytes + stats.material_bytes + stats.unreachable_bytes)
+            as f32
+            / MB
+    );
+}
@@ -0,0 +1,221 @@
+// Greedy meshing of a tree into an OBJ or PLY file.
+//
+// Every voxel face bordering an empty voxel is collected into a slice per axis,
+// direction and depth. Within a slice, faces of the same material are merged into
+// rectangles, growing each first along u and then along v.
+
+use fxhash::{FxHashMap, FxHashSet};
+use glam::IVec3;
+use rube::tree::{MaterialId, VoxelTree};
+use std::io::Write;
+use std::path::Path;
+
+pub fn export(tree: &VoxelTree, path: impl AsRef<Path>) -> std::io::Result<()> {
+    let path = path.as_ref();
+    println!("Meshing {}...", path.display());
+    let start = std::time::Instant::now();
+    let mesh = Mesh::greedy(tree);
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("obj") => mesh.write_obj(tree, path)?,
+        Some("ply") => mesh.write_ply(tree, path)?,
+        _ => {
+            return Err(std::io::Error::other(format!(
+                "{} is neither an .obj nor a .ply file",
+                path.display()
+            )));
+        }
+    }
+    println!("  Vertices: {}", mesh.vertices.len());
+    println!("  Quads: {}", mesh.quads.len());
+    println!("  [{:?}]", start.elapsed());
+    Ok(())
+}
+
+#[derive(Default)]
+struct Mesh {
+    vertices: Vec<IVec3>,
+    vertex_indices: FxHashMap<IVec3, u32>,
+    // Counterclockwise when seen from outside of the solid.
+    quads: Vec<([u32; 4], MaterialId)>,
+}
+
+// Faces of one direction in the plane at `depth` along `axis`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Slice {
+    axis: usize,
+    positive: bool,
+    depth: i32,
+}
+
+impl Mesh {
+    fn greedy(tree: &VoxelTree) -> Self {
+        let mut slices = FxHashMap::<Slice, FxHashMap<(i32, i32), MaterialId>>::default();
+        for (pos, material) in tree.voxels() {
+            for axis in 0..3 {
+                for positive in [false, true] {
+                    let mut normal = IVec3::ZERO;
+                    normal[axis] = if positive { 1 } else { -1 };
+                    if tree.get(pos + normal).is_some() {
+                        continue;
+                    }
+                    let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+                    let slice = Slice {
+                        axis,
+                        positive,
+                        depth: pos[axis] + positive as i32,
+                    };
+                    slices
+                        .entry(slice)
+                        .or_default()
+                        .insert((pos[u], pos[v]), material);
+                }
+            }
+        }
+
+        let mut mesh = Self::default();
+        let mut slices = slices.into_iter().collect::<Vec<_>>();
+        slices.sort_unstable_by_key(|(slice, _)| (slice.axis, slice.positive, slice.depth));
+        for (slice, faces) in slices {
+            let mut cells = faces.iter().map(|(&cell, _)| cell).collect::<Vec<_>>();
+            cells.sort_unstable_by_key(|&(u, v)| (v, u));
+            let mut merged = FxHashSet::default();
+            let mergeable = |cell: (i32, i32), material, merged: &FxHashSet<_>| {
+                faces.get(&cell) == Some(&material) && !merged.contains(&cell)
+            };
+            for (u, v) in cells {
+                if merged.contains(&(u, v)) {
+                    continue;
+                }
+                let material = faces[&(u, v)];
+                let mut width = 1;
+                while mergeable((u + width, v), material, &merged) {
+                    width += 1;
+                }
+                let mut height = 1;
+                while (0..width).all(|w| mergeable((u + w, v + height), material, &merged)) {
+                    height += 1;
+                }
+                for h in 0..height {
+                    for w in 0..width {
+                        merged.insert((u + w, v + h));
+                    }
+                }
+                mesh.quad(slice, (u, v), (width, height), material);
+            }
+        }
+        mesh
+    }
+
+    fn quad(
+        &mut self,
+        slice: Slice,
+        (u, v): (i32, i32),
+        (width, height): (i32, i32),
+        material: MaterialId,
+    ) {
+        let (u_axis, v_axis) = ((slice.axis + 1) % 3, (slice.axis + 2) % 3);
+        let corner = |du, dv| {
+            let mut pos = IVec3::ZERO;
+            pos[slice.axis] = slice.depth;
+            pos[u_axis] = u + du;
+            pos[v_axis] = v + dv;
+            pos
+        };
+        // u, v and the axis are right handed, so this order faces the positive axis.
+        let mut corners = [
+            corner(0, 0),
+            corner(width, 0),
+            corner(width, height),
+            corner(0, height),
+        ];
+        if !slice.positive {
+            corners.reverse();
+        }
+        let indices = corners.map(|corner| {
+            *self.vertex_indices.entry(corner).or_insert_with(|| {
+                self.vertices.push(corner);
+                self.vertices.len() as u32 - 1
+            })
+        });
+        self.quads.push((indices, material));
+    }
+
+    fn write_obj(&self, tree: &VoxelTree, path: &Path) -> std::io::Result<()> {
+        let mtl_path = path.with_extension("mtl");
+        let mut mtl = std::io::BufWriter::new(std::fs::File::create(&mtl_path)?);
+        let mut quads = self.quads.clone();
+        quads.sort_unstable_by_key(|&(_, material)| material);
+        let mut materials = quads
+            .iter()
+            .map(|&(_, material)| material)
+            .collect::<Vec<_>>();
+        materials.dedup();
+        for material in materials {
+            let [r, g, b] = srgb(tree, material).map(|c| c as f32 / 255.0);
+            writeln!(mtl, "newmtl m{material}")?;
+            writeln!(mtl, "Kd {r} {g} {b}")?;
+        }
+        mtl.flush()?;
+
+        let mut obj = std::io::BufWriter::new(std::fs::File::create(path)?);
+        writeln!(
+            obj,
+            "mtllib {}",
+            mtl_path.file_name().unwrap().to_string_lossy()
+        )?;
+        for vertex in &self.vertices {
+            writeln!(obj, "v {} {} {}", vertex.x, vertex.y, vertex.z)?;
+        }
+        let mut current = None;
+        for ([a, b, c, d], material) in quads {
+            if current != Some(material) {
+                writeln!(obj, "usemtl m{material}")?;
+                current = Some(material);
+            }
+            // OBJ indices start at 1.
+            writeln!(obj, "f {} {} {} {}", a + 1, b + 1, c + 1, d + 1)?;
+        }
+        obj.flush()
+    }
+
+    fn write_ply(&self, tree: &VoxelTree, path: &Path) -> std::io::Result<()> {
+        let mut ply = std::io::BufWriter::new(std::fs::File::create(path)?);
+        write!(
+            ply,
+            "ply\n\
+             format binary_little_endian 1.0\n\
+             comment {}\n\
+             element vertex {}\n\
+             property float x\n\
+             property float y\n\
+             property float z\n\
+             element face {}\n\
+             property list uchar uint vertex_indices\n\
+             property uchar red\n\
+             property uchar green\n\
+             property uchar blue\n\
+             end_header\n",
+            concat!("rube-voxelize ", env!("CARGO_PKG_VERSION")),
+            self.vertices.len(),
+            self.quads.len()
+        )?;
+        for vertex in &self.vertices {
+            for c in vertex.as_vec3().to_array() {
+                ply.write_all(&c.to_le_bytes())?;
+            }
+        }
+        for &(indices, material) in &self.quads {
+            ply.write_all(&[4])?;
+            for index in indices {
+                ply.write_all(&index.to_le_bytes())?;
+            }
+            ply.write_all(&srgb(tree, material))?;
+        }
+        ply.flush()
+    }
+}
+
+fn srgb(tree: &VoxelTree, material: MaterialId) -> [u8; 3] {
+    let albedo = tree.packed_srgb(material as usize);
+    [(albedo >> 16) as u8, (albedo >> 8) as u8, albedo as u8]
+}
@@ -1,9 +1,17 @@
 // Stolen from https://github.com/DeadlockCode/voxel_ray_traversal/blob/main/src/voxelize.rs
 
+use fxhash::FxHashMap;
 use glam::{IVec3, UVec3, Vec2, Vec3, Vec4};
+use image::RgbImage;
 use rube::map::{Brick, VoxelMap};
+use rube::material::Material;
+use rube::tree::MaterialId;
 use std::path::Path;
 
+/// Voxelizes an OBJ file into a map `resolution` voxels wide.
+///
+/// Every face color, see `face_color`, gets its own material after quantization, so
+/// textured and vertex colored meshes can use more than 256 materials.
 pub fn voxelize(path: impl AsRef<Path>, resolution: u32) -> VoxelMap {
     println!("Voxelizing {} @ {resolution}...", path.as_ref().display());
     let start = std::time::Instant::now();
@@ -15,8 +23,9 @@ pub fn voxelize(path: impl AsRef<Path>, resolution: u32) -> VoxelMap {
 }
 
 fn parse_obj(path: impl AsRef<Path>) -> Mesh {
-    let (models, _) = tobj::load_obj(
-        path.as_ref(),
+    let path = path.as_ref();
+    let (models, materials) = tobj::load_obj(
+        path,
         &tobj::LoadOptions {
             single_index: true,
             triangulate: true,
@@ -24,23 +33,81 @@ fn parse_obj(path: impl AsRef<Path>) -> Mesh {
         },
     )
     .expect("Failed to load OBJ file");
+    let materials = materials.unwrap_or_else(|err| {
+        println!("  [WARN] failed to load materials: {err}");
+        Vec::new()
+    });
+    // Diffuse textures are relative to the OBJ file.
+    let textures = materials
+        .iter()
+        .map(|material| {
+            let texture = path.with_file_name(material.diffuse_texture.as_ref()?);
+            match image::open(&texture) {
+                Ok(image) => Some(image.to_rgb8()),
+                Err(err) => {
+                    println!("  [WARN] failed to load {}: {err}", texture.display());
+                    None
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
     let mut vertices = Vec::new();
     let mut triangles = Vec::new();
+    let mut colors = Vec::new();
     for model in models {
         let mesh = &model.mesh;
+        let offset = vertices.len() as u32;
         for v in mesh.positions.chunks_exact(3) {
             vertices.push(Vec3::new(-v[0], v[2], v[1]));
         }
+        let material = mesh.material_id.and_then(|id| materials.get(id));
+        let texture = mesh.material_id.and_then(|id| textures.get(id)?.as_ref());
         for idx in mesh.indices.chunks_exact(3) {
-            triangles.push([idx[0], idx[1], idx[2]]);
+            triangles.push([offset + idx[0], offset + idx[1], offset + idx[2]]);
+            colors.push(face_color(mesh, idx, material, texture));
         }
     }
     Mesh {
         vertices,
         triangles,
+        colors,
     }
 }
 
+// sRGB color of a face from its vertex colors, or else from the diffuse color and
+// texture of its material at its center. Faces without either are white.
+fn face_color(
+    mesh: &tobj::Mesh,
+    face: &[u32],
+    material: Option<&tobj::Material>,
+    texture: Option<&RgbImage>,
+) -> Vec3 {
+    if !mesh.vertex_color.is_empty() {
+        let sum = face.iter().fold(Vec3::ZERO, |sum, &i| {
+            sum + Vec3::from_slice(&mesh.vertex_color[i as usize * 3..])
+        });
+        return sum / 3.0;
+    }
+    let mut color = material
+        .and_then(|material| material.diffuse)
+        .map_or(Vec3::ONE, Vec3::from_array);
+    if let Some(texture) = texture
+        && !mesh.texcoords.is_empty()
+    {
+        let uv = face.iter().fold(Vec2::ZERO, |sum, &i| {
+            sum + Vec2::from_slice(&mesh.texcoords[i as usize * 2..])
+        }) / 3.0;
+        // Textures repeat, and their rows start at the top.
+        let (width, height) = texture.dimensions();
+        let x = (uv.x.rem_euclid(1.0) * width as f32) as u32;
+        let y = ((1.0 - uv.y).rem_euclid(1.0) * height as f32) as u32;
+        let [r, g, b] = texture.get_pixel(x.min(width - 1), y.min(height - 1)).0;
+        color *= Vec3::new(r as f32, g as f32, b as f32) / 255.0;
+    }
+    color
+}
+
 fn transform_vertices(vertices: &mut [Vec3], resolution: u32) {
     let mut min = Vec3::MAX;
     let mut max = Vec3::MIN;
@@ -57,8 +124,24 @@ fn transform_vertices(vertices: &mut [Vec3], resolution: u32) {
 }
 
 fn voxelize_mesh(mesh: &Mesh) -> VoxelMap {
-    let mut map = VoxelMap::default();
-    for triangle in &mesh.triangles {
+    let mut map = VoxelMap {
+        materials: vec![Material::default()],
+        ..Default::default()
+    };
+    // Face colors are quantized to 5 bits per channel, which keeps the material table
+    // within 16-bit ids.
+    let mut ids = FxHashMap::<UVec3, MaterialId>::default();
+    for (triangle, color) in mesh.triangles.iter().zip(&mesh.colors) {
+        let quantized = (color.clamp(Vec3::ZERO, Vec3::ONE) * 31.0)
+            .round()
+            .as_uvec3();
+        let material = *ids.entry(quantized).or_insert_with(|| {
+            let srgb = (quantized * 255 + 15) / 31;
+            let albedo = 0xff000000 | (srgb.x << 16) | (srgb.y << 8) | srgb.z;
+            map.materials.push(Material::diffuse(albedo));
+            (map.materials.len() - 1) as MaterialId
+        });
+
         let a = mesh.vertices[triangle[0] as usize];
         let b = mesh.vertices[triangle[1] as usize];
         let c = mesh.vertices[triangle[2] as usize];
@@ -68,7 +151,7 @@ fn voxelize_mesh(mesh: &Mesh) -> VoxelMap {
             let brick_pos = voxel_pos >> 3;
             let brick = map.chunks.entry(brick_pos).or_default();
             let index = Brick::voxel_index(voxel_pos & 7);
-            brick.data[index] = 1;
+            brick.set(index, material);
         });
     }
     map
@@ -184,4 +267,6 @@ impl Helper {
 struct Mesh {
     vertices: Vec<Vec3>,
     triangles: Vec<[u32; 3]>,
+    // sRGB color of each triangle, see `face_color`.
+    colors: Vec<Vec3>,
 }
@@ -1,6 +1,9 @@
-use dot_vox::{Frame, Model, SceneNode};
+use dot_vox::{Color, Dict, DotVoxData, Frame, Layer, Model, SceneNode, ShapeModel, Size, Voxel};
+use fxhash::FxHashMap;
 use glam::{IVec3, Mat3, Vec3};
 use rube::map::{Brick, VoxelMap};
+use rube::material::Material;
+use rube::tree::MaterialId;
 use std::path::Path;
 
 pub fn voxelize(path: impl AsRef<Path>) -> VoxelMap {
@@ -27,6 +30,35 @@ pub fn voxelize(path: impl AsRef<Path>) -> VoxelMap {
         Some(Mat3::from_cols(cols[0], cols[1], cols[2]))
     }
 
+    // Properties that don't apply to a material's `_type` are absent or left at their
+    // defaults, so they can be read regardless of it.
+    fn parse_material(properties: &Dict, albedo: u32) -> Material {
+        let property = |key: &str| {
+            properties
+                .get(key)
+                .and_then(|v| v.trim().parse::<f32>().ok())
+        };
+        let mut material = Material::diffuse(albedo);
+        if let Some(rough) = property("_rough") {
+            material.roughness = rough;
+        }
+        if let Some(metal) = property("_metal") {
+            material.metalness = metal;
+        }
+        if let Some(trans) = property("_trans").or_else(|| property("_alpha")) {
+            material.opacity = 1.0 - trans;
+        }
+        // `_ri` is the refractive index, older files only store `_ior` offset by one.
+        if let Some(ior) = property("_ri").or_else(|| property("_ior").map(|ior| ior + 1.0)) {
+            material.ior = ior;
+        }
+        if let Some(emit) = property("_emit") {
+            // `_flux` boosts the emission in powers of ten.
+            material.emissive_strength = emit * 10f32.powf(property("_flux").unwrap_or(0.0));
+        }
+        material
+    }
+
     fn descend_tree(
         map: &mut VoxelMap,
         scene_index: usize,
@@ -53,7 +85,7 @@ pub fn voxelize(path: impl AsRef<Path>) -> VoxelMap {
                         let brick_pos = voxel_pos >> 3;
                         let brick = map.chunks.entry(brick_pos).or_default();
                         let index = Brick::voxel_index(voxel_pos & 7);
-                        brick.data[index] = voxel.i;
+                        brick.set(index, voxel.i as MaterialId);
                     }
                 }
             }
@@ -97,7 +129,17 @@ pub fn voxelize(path: impl AsRef<Path>) -> VoxelMap {
     let vox = dot_vox::load(path.as_ref().to_str().unwrap()).unwrap();
     let mut map = VoxelMap::default();
     for (i, color) in vox.palette.iter().enumerate() {
-        map.palette[i] = (color.b as u32) | ((color.g as u32) << 8) | ((color.r as u32) << 16);
+        let albedo = (color.b as u32) | ((color.g as u32) << 8) | ((color.r as u32) << 16);
+        map.materials[i] = Material::diffuse(albedo);
+    }
+    // Material ids start at 1 like the palette indices stored in the file.
+    for vox_material in vox.materials.iter() {
+        let Some(i) = (vox_material.id as usize).checked_sub(1) else {
+            continue;
+        };
+        if let Some(material) = map.materials.get_mut(i) {
+            *material = parse_material(&vox_material.properties, material.albedo);
+        }
     }
     descend_tree(
         &mut map,
@@ -111,3 +153,165 @@ pub fn voxelize(path: impl AsRef<Path>) -> VoxelMap {
     println!("  [{:?}]", start.elapsed());
     map
 }
+
+/// Writes `map` as a MagicaVoxel scene.
+///
+/// Models are limited to 256 voxels along each axis, so the map is split into
+/// 256³ regions, each stored as its own model and placed with a transform node.
+pub fn export(map: &VoxelMap, path: impl AsRef<Path>) -> std::io::Result<()> {
+    println!("Exporting {}...", path.as_ref().display());
+    let start = std::time::Instant::now();
+
+    // Undo the y/z swap done in `voxelize`, MagicaVoxel is z up.
+    let mut regions = FxHashMap::<IVec3, Vec<Voxel>>::default();
+    let mut skipped = 0;
+    for (brick_pos, brick) in &map.chunks {
+        for (index, material) in brick.iter().enumerate() {
+            if material == 0 {
+                continue;
+            }
+            // The file stores palette indices offset by one in a byte, so only 255
+            // materials can be addressed.
+            if material >= u8::MAX as MaterialId {
+                skipped += 1;
+                continue;
+            }
+            let index = index as i32;
+            let pos = *brick_pos * 8 + IVec3::new(index & 7, index >> 6, (index >> 3) & 7);
+            let vox_pos = IVec3::new(pos.x, pos.z, pos.y);
+            regions.entry(vox_pos >> 8).or_default().push(Voxel {
+                x: (vox_pos.x & 255) as u8,
+                y: (vox_pos.y & 255) as u8,
+                z: (vox_pos.z & 255) as u8,
+                i: material as u8,
+            });
+        }
+    }
+    let mut regions = regions.into_iter().collect::<Vec<_>>();
+    regions.sort_unstable_by_key(|(region, _)| region.to_array());
+
+    // Root transform -> group -> (transform -> shape) per model
+    let mut scenes = vec![
+        SceneNode::Transform {
+            attributes: Default::default(),
+            frames: vec![Frame {
+                attributes: Default::default(),
+            }],
+            child: 1,
+            layer_id: u32::MAX,
+        },
+        SceneNode::Group {
+            attributes: Default::default(),
+            children: Vec::new(),
+        },
+    ];
+    let mut models = Vec::with_capacity(regions.len());
+    for (region, voxels) in regions {
+        let size = voxels.iter().fold(IVec3::ONE, |size, voxel| {
+            size.max(IVec3::new(voxel.x as i32, voxel.y as i32, voxel.z as i32) + 1)
+        });
+        // `voxelize` places voxels relative to the center of their model.
+        let translation = region * 256 + size / 2;
+        let transform = scenes.len() as u32;
+        if let SceneNode::Group { children, .. } = &mut scenes[1] {
+            children.push(transform);
+        }
+        scenes.push(SceneNode::Transform {
+            attributes: Default::default(),
+            frames: vec![Frame {
+                attributes: [(
+                    "_t".to_string(),
+                    format!("{} {} {}", translation.x, translation.y, translation.z),
+                )]
+                .into_iter()
+                .collect(),
+            }],
+            child: transform + 1,
+            layer_id: 0,
+        });
+        scenes.push(SceneNode::Shape {
+            attributes: Default::default(),
+            models: vec![ShapeModel {
+                model_id: models.len() as u32,
+                attributes: Default::default(),
+            }],
+        });
+        models.push(Model {
+            size: Size {
+                x: size.x as u32,
+                y: size.y as u32,
+                z: size.z as u32,
+            },
+            voxels,
+        });
+    }
+
+    let palette = map
+        .materials
+        .iter()
+        .take(256)
+        .map(|material| Color {
+            r: (material.albedo >> 16) as u8,
+            g: (material.albedo >> 8) as u8,
+            b: material.albedo as u8,
+            a: 255,
+        })
+        .collect();
+    // MagicaVoxel emits light in the albedo color, so `Material::emissive` is lost.
+    let materials = map
+        .materials
+        .iter()
+        .take(u8::MAX as usize)
+        .enumerate()
+        .filter(|(_, material)| **material != Material::diffuse(material.albedo))
+        .map(|(i, material)| {
+            let kind = if material.emissive_strength > 0.0 {
+                "_emit"
+            } else if material.opacity < 1.0 {
+                "_glass"
+            } else if material.metalness > 0.0 {
+                "_metal"
+            } else {
+                "_diffuse"
+            };
+            let properties = [
+                ("_type", kind.to_string()),
+                ("_rough", material.roughness.to_string()),
+                ("_metal", material.metalness.to_string()),
+                ("_trans", (1.0 - material.opacity).to_string()),
+                ("_ri", material.ior.to_string()),
+                ("_ior", (material.ior - 1.0).to_string()),
+                ("_emit", material.emissive_strength.min(1.0).to_string()),
+                (
+                    "_flux",
+                    material.emissive_strength.max(1.0).log10().to_string(),
+                ),
+            ];
+            dot_vox::Material {
+                id: i as u32 + 1,
+                properties: properties
+                    .into_iter()
+                    .map(|(key, value)| (key.to_string(), value))
+                    .collect(),
+            }
+        })
+        .collect();
+    let vox = DotVoxData {
+        version: 150,
+        index_map: Vec::new(),
+        models,
+        palette,
+        materials,
+        scenes,
+        layers: vec![Layer {
+            attributes: Default::default(),
+        }],
+    };
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    vox.write_vox(&mut file)?;
+    if skipped > 0 {
+        println!("  [WARN] skipped {skipped} voxels using materials above 254");
+    }
+    println!("  [{:?}]", start.elapsed());
+    Ok(())
+}
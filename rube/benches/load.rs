@@ -0,0 +1,60 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use rube::format::{Compression, Header, encode};
+use rube::tree::VoxelTree;
+use std::hint::black_box;
+
+const CODECS: [Compression; 5] = [
+    Compression::Bzip2,
+    Compression::Zstd,
+    Compression::Lz4,
+    Compression::None,
+    Compression::Mapped,
+];
+
+/// Loads every tree in `assets/` re-encoded with each codec.
+///
+/// `Mapped` is loaded from disk with `VoxelTree::open`, the other codecs are decoded
+/// from memory so that only decompression and deserialization are measured.
+fn criterion_benchmark(c: &mut Criterion) {
+    let assets = concat!(env!("CARGO_MANIFEST_DIR"), "/../assets");
+    let mut paths = std::fs::read_dir(assets)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.to_string_lossy().contains(".bin"))
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    for path in paths {
+        let bytes = std::fs::read(&path).unwrap();
+        let Ok(tree) = VoxelTree::decompress(&bytes) else {
+            continue;
+        };
+        let source = Header::read(&bytes)
+            .unwrap()
+            .map(|(header, _)| header.source)
+            .unwrap_or_default();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let mut group = c.benchmark_group(format!("load/{name}"));
+        group.sample_size(10);
+        for compression in CODECS {
+            let encoded = encode(&tree, compression, source.clone());
+            println!("{name} {compression:?}: {} bytes", encoded.len());
+            if compression == Compression::Mapped {
+                let mapped = std::env::temp_dir().join(format!("{name}.mapped.bin"));
+                std::fs::write(&mapped, &encoded).unwrap();
+                group.bench_function(format!("{compression:?}"), |b| {
+                    // SAFETY: The file is only written before the benchmark.
+                    b.iter(|| unsafe { VoxelTree::open(black_box(&mapped)) }.unwrap())
+                });
+            } else {
+                group.bench_function(format!("{compression:?}"), |b| {
+                    b.iter(|| VoxelTree::decompress(black_box(&encoded)).unwrap())
+                });
+            }
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -1,5 +1,5 @@
 use criterion::{Criterion, criterion_group, criterion_main};
-use rube::{indirect::IndirectPass, march::MarchPass, scene::Scene};
+use rube::{indirect::IndirectPass, march::MarchPass, ray::Ray, scene::Scene};
 use std::hint::black_box;
 
 fn criterion_benchmark(c: &mut Criterion) {
@@ -37,6 +37,31 @@ fn criterion_benchmark(c: &mut Criterion) {
             criterion::BatchSize::LargeInput,
         )
     });
+
+    let shadow_rays = march_pass
+        .hits
+        .iter()
+        .filter(|hit| !hit.missed())
+        .map(|hit| Ray::new(hit.position + hit.normal() * 1e-4, scene.light.direction))
+        .collect::<Vec<_>>();
+    let mut group = c.benchmark_group("shadow_rays");
+    group.bench_function("cast", |b| {
+        b.iter(|| {
+            shadow_rays
+                .iter()
+                .filter(|ray| !ray.cast(black_box(&scene.tree)).missed())
+                .count()
+        })
+    });
+    group.bench_function("occluded", |b| {
+        b.iter(|| {
+            shadow_rays
+                .iter()
+                .filter(|ray| ray.occluded(black_box(&scene.tree)))
+                .count()
+        })
+    });
+    group.finish();
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -76,11 +101,11 @@ criterion_main!(benches);
 //   4 (4.00%) high mild
 
 // occlusion opt
-// 
+//
 // march_pass              time:   [5.2674 ms 5.2848 ms 5.3042 ms]
 // Found 5 outliers among 100 measurements (5.00%)
 //   5 (5.00%) high mild
-// 
+//
 // indirect_pass           time:   [8.9427 ms 8.9866 ms 9.0433 ms]
 // Found 3 outliers among 100 measurements (3.00%)
 //   1 (1.00%) high mild
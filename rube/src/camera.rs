@@ -1,4 +1,7 @@
-use crate::{ray::Ray, tree::VoxelTree};
+use crate::{
+    ray::{Outcome, Ray},
+    tree::VoxelTree,
+};
 use glam::{Mat4, Vec2, Vec3};
 use rube_platform::winit::{event::ElementState, keyboard::KeyCode};
 use std::f32::consts::FRAC_PI_2;
@@ -11,6 +14,9 @@ pub struct Camera {
     pub fov: f32,
     pub znear: f32,
     pub zfar: f32,
+    /// Coarsens LOD by this many powers of two. At `0` rays stop at the first cells
+    /// that fit into their pixel.
+    pub lod_bias: f32,
     pub speed: f32,
     pub half_speed: bool,
     pub left: bool,
@@ -41,6 +47,12 @@ impl Camera {
             KeyCode::KeyF if state.is_pressed() => {
                 self.flying = !self.flying;
             }
+            KeyCode::BracketLeft if state.is_pressed() => {
+                self.lod_bias -= 1.0;
+            }
+            KeyCode::BracketRight if state.is_pressed() => {
+                self.lod_bias += 1.0;
+            }
             KeyCode::KeyA => {
                 self.left = state.is_pressed();
             }
@@ -107,10 +119,20 @@ impl Camera {
                 let new_translation = self.translation + self.exp_decay_translation;
 
                 let height = 0.004;
-                let hit =
-                    Ray::new(new_translation + Vec3::Y * height / 4.0, Vec3::NEG_Y).cast(tree);
-                if hit.position != Vec3::ZERO {
-                    frame_dt.y = ((hit.position.y + height) - new_translation.y) * 10.0 * dt;
+                let origin = new_translation + Vec3::Y * height / 4.0;
+                // Ground farther down than this is approached as if it was at the end of
+                // the range, so falling doesn't trace to the bottom of the world.
+                let max_fall = height * 16.0;
+                let hit = Ray::new(origin, Vec3::NEG_Y)
+                    .range(0.0, max_fall)
+                    .cast(tree);
+                let ground = match hit.outcome() {
+                    Outcome::Hit => Some(hit.position.y),
+                    Outcome::MaxDistance => Some(origin.y - max_fall),
+                    Outcome::Escaped | Outcome::MaxIterations => None,
+                };
+                if let Some(ground) = ground {
+                    frame_dt.y = ((ground + height) - new_translation.y) * 10.0 * dt;
                     self.exp_decay_translation = self.exp_decay_translation * 0.8 + frame_dt * 0.2;
                     self.translation += self.exp_decay_translation;
                 }
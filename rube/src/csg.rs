@@ -0,0 +1,428 @@
+// Boolean operations between a `VoxelTree` and another tree or an analytic shape.
+//
+// Like `VoxelTree::edit`, operations are copy-on-write: only subtrees where both
+// operands overlap are rebuilt and appended to `nodes`/`leaves`, everything else keeps
+// pointing at its existing data. Empty cells are skipped through `Node::mask`, and
+// regions a shape fully covers are filled with a single shared subtree per level.
+
+use crate::ray::{bit, cell_pos, popcnt};
+use crate::tree::{MaterialId, Node, VoxelTree, next_generation};
+use fxhash::FxHashMap;
+use glam::IVec3;
+
+/// How a `Shape` covers a region of voxels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overlap {
+    Empty,
+    Partial,
+    Full,
+}
+
+/// An analytic solid that can be combined with a `VoxelTree`.
+pub trait Shape {
+    /// Returns how the shape covers the voxels in `min..max`.
+    ///
+    /// Single voxels must be `Empty` or `Full`. `Partial` is always correct for larger
+    /// regions but makes the operation descend into them.
+    fn overlap(&self, min: IVec3, max: IVec3) -> Overlap;
+}
+
+/// The voxels in `min..max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Aabb {
+    pub min: IVec3,
+    pub max: IVec3,
+}
+
+impl Shape for Aabb {
+    fn overlap(&self, min: IVec3, max: IVec3) -> Overlap {
+        if min.cmpge(self.max).any() || max.cmple(self.min).any() {
+            Overlap::Empty
+        } else if min.cmpge(self.min).all() && max.cmple(self.max).all() {
+            Overlap::Full
+        } else {
+            Overlap::Partial
+        }
+    }
+}
+
+/// The voxels within `radius` of `center`, as in `VoxelTree::set_sphere`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sphere {
+    pub center: IVec3,
+    pub radius: u32,
+}
+
+impl Shape for Sphere {
+    fn overlap(&self, min: IVec3, max: IVec3) -> Overlap {
+        let center = self.center.as_i64vec3();
+        let (min, max) = (min.as_i64vec3(), max.as_i64vec3() - 1);
+        let radius_squared = self.radius as i64 * self.radius as i64;
+        let nearest = center.clamp(min, max) - center;
+        let farthest = (min - center).abs().max((max - center).abs());
+        if nearest.length_squared() > radius_squared {
+            Overlap::Empty
+        } else if farthest.length_squared() <= radius_squared {
+            Overlap::Full
+        } else {
+            Overlap::Partial
+        }
+    }
+}
+
+impl VoxelTree {
+    /// Adds the voxels of `other`, which replace overlapping voxels of this tree.
+    ///
+    /// `other` is placed at the origin and its materials are appended to
+    /// `VoxelTree::materials` unless an identical one exists. Unloaded pages of either
+    /// tree are left unchanged.
+    ///
+    /// Panics if `other` is deeper than this tree.
+    pub fn union(&mut self, other: &VoxelTree) {
+        self.combine_tree(Op::Union, other);
+    }
+
+    /// Removes every voxel that is solid in `other`, placed at the origin.
+    ///
+    /// Regions under unloaded pages of either tree are left unchanged, as the voxels
+    /// of `other` there are unknown.
+    ///
+    /// Panics if `other` is deeper than this tree.
+    pub fn subtract(&mut self, other: &VoxelTree) {
+        self.combine_tree(Op::Subtract, other);
+    }
+
+    /// Removes every voxel that is empty in `other`, placed at the origin.
+    ///
+    /// Regions under unloaded pages of either tree are left unchanged, as the voxels
+    /// of `other` there are unknown.
+    ///
+    /// Panics if `other` is deeper than this tree.
+    pub fn intersect(&mut self, other: &VoxelTree) {
+        self.combine_tree(Op::Intersect, other);
+    }
+
+    /// Sets every voxel inside `shape` to `material`, which must not be `0` and must be
+    /// in `materials`.
+    pub fn union_shape(&mut self, shape: &impl Shape, material: MaterialId) {
+        assert_ne!(
+            material, 0,
+            "use `VoxelTree::subtract_shape` to remove voxels"
+        );
+        self.check_material(material);
+        self.combine(Op::Union, Other::Shape { shape, material });
+    }
+
+    /// Removes every voxel inside `shape`.
+    pub fn subtract_shape(&mut self, shape: &impl Shape) {
+        self.combine(Op::Subtract, Other::Shape { shape, material: 1 });
+    }
+
+    /// Removes every voxel outside of `shape`.
+    pub fn intersect_shape(&mut self, shape: &impl Shape) {
+        self.combine(Op::Intersect, Other::Shape { shape, material: 1 });
+    }
+
+    fn combine_tree(&mut self, op: Op, other: &VoxelTree) {
+        assert!(
+            other.exp <= self.exp,
+            "a tree of 2^{} can't be combined into a tree of 2^{}",
+            other.exp,
+            self.exp
+        );
+        let materials = if op == Op::Union {
+            other
+                .materials
+                .iter()
+                .enumerate()
+                .map(|(id, material)| {
+                    if id == 0 {
+                        return 0;
+                    }
+                    let existing = self.materials[1..].iter().position(|m| m == material);
+                    let id = existing.map_or_else(
+                        || {
+                            self.materials.push(*material);
+                            self.materials.len() - 1
+                        },
+                        |index| index + 1,
+                    );
+                    MaterialId::try_from(id).expect("too many materials for a `MaterialId`")
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        self.combine(
+            op,
+            Other::Tree {
+                tree: other,
+                materials,
+            },
+        );
+    }
+
+    fn combine(&mut self, op: Op, other: Other) {
+        self.generation = next_generation();
+        let root = match &other {
+            Other::Tree { tree, .. } if tree.exp == self.exp => Operand::Node(tree.nodes[0]),
+            Other::Tree { .. } => Operand::Above,
+            Other::Shape { shape, .. } => {
+                Operand::from(shape.overlap(IVec3::ZERO, IVec3::splat(1 << self.exp)))
+            }
+        };
+        let mut csg = Csg {
+            op,
+            other,
+            filled: FxHashMap::default(),
+            copied: FxHashMap::default(),
+        };
+        let node = self.nodes[0];
+        self.nodes[0] = csg.node(self, node, self.exp, IVec3::ZERO, root);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Union,
+    Subtract,
+    Intersect,
+}
+
+enum Other<'a> {
+    Tree {
+        tree: &'a VoxelTree,
+        // Maps material ids of `tree` to the combined tree, only used by unions.
+        materials: Vec<MaterialId>,
+    },
+    Shape {
+        shape: &'a dyn Shape,
+        material: MaterialId,
+    },
+}
+
+// The other operand within a single node of the tree being combined.
+#[derive(Clone, Copy)]
+enum Operand {
+    Empty,
+    Full,
+    Partial,
+    Node(Node),
+    // A node whose first cell leads to the root of a shallower tree.
+    Above,
+}
+
+impl From<Overlap> for Operand {
+    fn from(overlap: Overlap) -> Self {
+        match overlap {
+            Overlap::Empty => Self::Empty,
+            Overlap::Partial => Self::Partial,
+            Overlap::Full => Self::Full,
+        }
+    }
+}
+
+struct Csg<'a> {
+    op: Op,
+    other: Other<'a>,
+    // Subtrees filled with the material of a shape, keyed by scale.
+    filled: FxHashMap<u32, Node>,
+    // Subtrees of another tree copied by a union, keyed by mask and
+    // `child_index_is_leaf` as leaf groups are shared regardless of the mask.
+    copied: FxHashMap<(u64, u32), Node>,
+}
+
+impl Csg<'_> {
+    fn node(
+        &mut self,
+        tree: &mut VoxelTree,
+        node: Node,
+        scale: u32,
+        pos: IVec3,
+        other: Operand,
+    ) -> Node {
+        if !node.is_loaded() {
+            return node;
+        }
+        match (self.op, other) {
+            (_, Operand::Node(other)) if other.mask == 0 => {
+                return self.node(tree, node, scale, pos, Operand::Empty);
+            }
+            // The voxels of `other` are unknown, so none of the operations can tell
+            // what to keep.
+            (_, Operand::Node(other)) if !other.is_loaded() => return node,
+            (Op::Union | Op::Subtract, Operand::Empty) | (Op::Intersect, Operand::Full) => {
+                return node;
+            }
+            (Op::Subtract, Operand::Full) | (Op::Intersect, Operand::Empty) => {
+                return Node::default();
+            }
+            (Op::Union, Operand::Full) => return self.filled(tree, scale),
+            (Op::Union, Operand::Node(other)) if node.mask == 0 => {
+                // Falls back to descending if part of `other` is unloaded.
+                if let Some(copied) = self.copy(tree, other) {
+                    return copied;
+                }
+            }
+            (Op::Subtract | Op::Intersect, _) if node.mask == 0 => return node,
+            _ => {}
+        }
+
+        if scale == 2 {
+            // Cells are indexed by `x + z*4 + y*16`
+            let mut cells = [0; 64];
+            for (i, cell) in cells.iter_mut().enumerate() {
+                if bit(node.mask, i) {
+                    *cell = tree.leaves.get(node.child_index() + popcnt(node.mask, i));
+                }
+            }
+            let mut combined = cells;
+            for (i, cell) in combined.iter_mut().enumerate() {
+                let material = self.other_voxel(other, pos + cell_pos(i), i);
+                *cell = match self.op {
+                    Op::Union if material != 0 => material,
+                    Op::Subtract if material != 0 => 0,
+                    Op::Intersect if material == 0 => 0,
+                    _ => *cell,
+                };
+            }
+            if combined == cells {
+                return node;
+            }
+            return tree.push_leaf_node(&combined);
+        }
+
+        let scale = scale - 2;
+        let mut children = [Node::default(); 64];
+        for (i, child) in children.iter_mut().enumerate() {
+            if bit(node.mask, i) {
+                *child = tree.nodes[node.child_index() + popcnt(node.mask, i)];
+            }
+        }
+        let mut changed = false;
+        for (i, child) in children.iter_mut().enumerate() {
+            let child_pos = pos + (cell_pos(i) << scale);
+            let other = self.other_child(other, scale, child_pos, i);
+            let combined = self.node(tree, *child, scale, child_pos, other);
+            changed |= combined != *child;
+            *child = combined;
+        }
+        if !changed {
+            return node;
+        }
+        tree.push_interior_node(&children)
+    }
+
+    // Returns the other operand in cell `i` of a node, where the cell spans `2^scale`
+    // voxels from `pos`.
+    fn other_child(&self, other: Operand, scale: u32, pos: IVec3, i: usize) -> Operand {
+        match (other, &self.other) {
+            (Operand::Node(node), Other::Tree { tree, .. }) => {
+                if bit(node.mask, i) {
+                    Operand::Node(tree.nodes[node.child_index() + popcnt(node.mask, i)])
+                } else {
+                    Operand::Empty
+                }
+            }
+            (Operand::Above, Other::Tree { tree, .. }) => match i {
+                0 if scale == tree.exp => Operand::Node(tree.nodes[0]),
+                0 => Operand::Above,
+                _ => Operand::Empty,
+            },
+            (Operand::Partial, Other::Shape { shape, .. }) => {
+                Operand::from(shape.overlap(pos, pos + IVec3::splat(1 << scale)))
+            }
+            _ => other,
+        }
+    }
+
+    // Returns the material of the other operand at voxel `pos`, cell `i` of a leaf node.
+    fn other_voxel(&self, other: Operand, pos: IVec3, i: usize) -> MaterialId {
+        match (other, &self.other) {
+            (Operand::Node(node), Other::Tree { tree, materials }) if bit(node.mask, i) => {
+                let material = tree.leaves.get(node.child_index() + popcnt(node.mask, i));
+                // Only unions remap materials, other operations just test for solid voxels.
+                materials
+                    .get(material as usize)
+                    .copied()
+                    .unwrap_or(material)
+            }
+            (Operand::Partial, Other::Shape { shape, material }) => {
+                match shape.overlap(pos, pos + IVec3::ONE) {
+                    Overlap::Full => *material,
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    // Returns a subtree of `2^scale` voxels filled with the material of the shape.
+    fn filled(&mut self, tree: &mut VoxelTree, scale: u32) -> Node {
+        if let Some(&node) = self.filled.get(&scale) {
+            return node;
+        }
+        let Other::Shape { material, .. } = self.other else {
+            unreachable!("only shapes report full coverage");
+        };
+        let node = if scale == 2 {
+            tree.push_leaf_node(&[material; 64])
+        } else {
+            let child = self.filled(tree, scale - 2);
+            tree.push_interior_node(&[child; 64])
+        };
+        self.filled.insert(scale, node);
+        node
+    }
+
+    // Copies a subtree of the other tree, keeping its layout, attribute offsets and mip
+    // maps. Returns `None` if part of it is unloaded.
+    fn copy(&mut self, tree: &mut VoxelTree, node: Node) -> Option<Node> {
+        if !node.is_loaded() {
+            return None;
+        }
+        if let Some(&copied) = self.copied.get(&(node.mask, node.child_index_is_leaf)) {
+            return Some(copied);
+        }
+        let Other::Tree {
+            tree: other,
+            materials,
+        } = &self.other
+        else {
+            unreachable!("only trees are copied");
+        };
+        let start = node.child_index();
+        let end = start + node.mask.count_ones() as usize;
+        let child_index_is_leaf = if node.is_leaf() {
+            let active_leaves = other
+                .leaves
+                .to_vec(start..end)
+                .into_iter()
+                .map(|material| materials[material as usize])
+                .collect::<Vec<_>>();
+            let leaf_index = tree.leaves.len() as u32;
+            tree.leaves.extend_from_slice(&active_leaves);
+            (leaf_index << 1) | 1
+        } else {
+            let children = other.nodes[start..end]
+                .iter()
+                .map(|&child| {
+                    Some(Node {
+                        attribute_offset: child.attribute_offset,
+                        ..self.copy(tree, child)?
+                    })
+                })
+                .collect::<Option<Vec<_>>>()?;
+            let child_index = tree.nodes.len() as u32;
+            tree.nodes.to_mut().extend_from_slice(&children);
+            child_index << 1
+        };
+        let copied = Node {
+            child_index_is_leaf,
+            ..node
+        };
+        self.copied
+            .insert((node.mask, node.child_index_is_leaf), copied);
+        Some(copied)
+    }
+}
@@ -0,0 +1,256 @@
+// Runtime editing of a `VoxelTree`.
+//
+// Child arrays and leaf groups may be shared between identical subtrees, so edits
+// never mutate them in place. Instead, every subtree overlapping the edited region
+// is rebuilt and appended to `nodes`/`leaves`, while untouched siblings keep pointing
+// at their existing (possibly shared) data. The replaced arrays are left
+// unreachable until `VoxelTree::compact` is called.
+
+use crate::ray::{bit, cell_pos, popcnt};
+use crate::tree::{
+    Leaves, MaterialId, Node, NodeHash, VoxelTree, interior_mip_map, leaf_mip_map, next_generation,
+};
+use fxhash::FxHashMap;
+use glam::IVec3;
+
+impl VoxelTree {
+    /// Sets the voxel at `pos` to `material`, where `0` is empty.
+    ///
+    /// Panics if `material` is outside of `materials`, like every setter below.
+    pub fn set(&mut self, pos: IVec3, material: MaterialId) {
+        self.check_material(material);
+        self.edit(pos, pos + IVec3::ONE, |_, _| material);
+    }
+
+    /// Removes the voxel at `pos`.
+    pub fn clear(&mut self, pos: IVec3) {
+        self.set(pos, 0);
+    }
+
+    /// Sets every voxel in `min..max` to `material`.
+    pub fn set_box(&mut self, min: IVec3, max: IVec3, material: MaterialId) {
+        self.check_material(material);
+        self.edit(min, max, |_, _| material);
+    }
+
+    /// Removes every voxel in `min..max`.
+    pub fn clear_box(&mut self, min: IVec3, max: IVec3) {
+        self.set_box(min, max, 0);
+    }
+
+    /// Sets every voxel within `radius` of `center` to `material`.
+    pub fn set_sphere(&mut self, center: IVec3, radius: u32, material: MaterialId) {
+        self.check_material(material);
+        let r = radius as i32;
+        self.edit(
+            center - IVec3::splat(r),
+            center + IVec3::splat(r + 1),
+            |pos, current| {
+                if (pos - center).length_squared() <= r * r {
+                    material
+                } else {
+                    current
+                }
+            },
+        );
+    }
+
+    /// Removes every voxel within `radius` of `center`.
+    pub fn clear_sphere(&mut self, center: IVec3, radius: u32) {
+        self.set_sphere(center, radius, 0);
+    }
+
+    /// Replaces every voxel in `min..max` with `f(pos, material)`, where `0` is empty.
+    ///
+    /// The region is clamped to the bounds of the tree. Masks, leaves, attribute
+    /// offsets and mip maps are rebuilt along every path that changed. Narrow leaves
+    /// are widened if `f` returns an id above 255. Unloaded pages are left unchanged.
+    ///
+    /// Panics if `f` returns an id outside of `materials`.
+    pub fn edit(
+        &mut self,
+        min: IVec3,
+        max: IVec3,
+        mut f: impl FnMut(IVec3, MaterialId) -> MaterialId,
+    ) {
+        let min = min.max(IVec3::ZERO);
+        let max = max.min(IVec3::splat(1 << self.exp));
+        if min.cmpge(max).any() {
+            return;
+        }
+        self.generation = next_generation();
+        let root = self.nodes[0];
+        self.nodes[0] = self.edit_node(root, self.exp, IVec3::ZERO, min, max, &mut f);
+    }
+
+    pub(crate) fn check_material(&self, material: MaterialId) {
+        assert!(
+            (material as usize) < self.materials.len(),
+            "material {material} is outside of the {} materials of the tree",
+            self.materials.len()
+        );
+    }
+
+    fn edit_node(
+        &mut self,
+        node: Node,
+        mut scale: u32,
+        pos: IVec3,
+        min: IVec3,
+        max: IVec3,
+        f: &mut impl FnMut(IVec3, MaterialId) -> MaterialId,
+    ) -> Node {
+        if !node.is_loaded() {
+            return node;
+        }
+        if scale == 2 {
+            // Cells are indexed by `x + z*4 + y*16`
+            let mut cells = [0; 64];
+            for (i, cell) in cells.iter_mut().enumerate() {
+                if bit(node.mask, i) {
+                    *cell = self.leaves.get(node.child_index() + popcnt(node.mask, i));
+                }
+            }
+            let mut edited = cells;
+            for (i, cell) in edited.iter_mut().enumerate() {
+                let voxel_pos = pos + cell_pos(i);
+                if voxel_pos.cmpge(min).all() && voxel_pos.cmplt(max).all() {
+                    *cell = f(voxel_pos, *cell);
+                    self.check_material(*cell);
+                }
+            }
+            if edited == cells {
+                return node;
+            }
+            return self.push_leaf_node(&edited);
+        }
+
+        scale -= 2;
+        let mut children = [Node::default(); 64];
+        for (i, child) in children.iter_mut().enumerate() {
+            if bit(node.mask, i) {
+                *child = self.nodes[node.child_index() + popcnt(node.mask, i)];
+            }
+        }
+        let mut changed = false;
+        for (i, child) in children.iter_mut().enumerate() {
+            let child_pos = pos + (cell_pos(i) << scale);
+            let child_max = child_pos + IVec3::splat(1 << scale);
+            if child_pos.cmplt(max).all() && min.cmplt(child_max).all() {
+                let edited = self.edit_node(*child, scale, child_pos, min, max, f);
+                changed |= edited != *child;
+                *child = edited;
+            }
+        }
+        if !changed {
+            return node;
+        }
+        self.push_interior_node(&children)
+    }
+
+    /// Recomputes every `Node::mip_map` reachable from the root, e.g. after changing
+    /// `materials`. Unloaded pages keep their mip maps.
+    pub fn rebuild_mip_maps(&mut self) {
+        self.generation = next_generation();
+        let root = self.nodes[0];
+        let mip_map = self.rebuild_mip_map(root, &mut FxHashMap::default());
+        self.nodes[0].mip_map = mip_map;
+    }
+
+    // Shared arrays are updated in place, which is safe as identical subtrees have
+    // identical mip maps. `rebuilt` is keyed by mask and `child_index_is_leaf`.
+    fn rebuild_mip_map(&mut self, node: Node, rebuilt: &mut FxHashMap<(u64, u32), u32>) -> u32 {
+        if node.mask == 0 || !node.is_loaded() {
+            return node.mip_map;
+        }
+        if let Some(&mip_map) = rebuilt.get(&(node.mask, node.child_index_is_leaf)) {
+            return mip_map;
+        }
+        let start = node.child_index();
+        let end = start + node.mask.count_ones() as usize;
+        let mip_map = if node.is_leaf() {
+            leaf_mip_map(&self.materials, node.mask, &self.leaves.to_vec(start..end))
+        } else {
+            for index in start..end {
+                let child = self.nodes[index];
+                let mip_map = self.rebuild_mip_map(child, rebuilt);
+                self.nodes[index].mip_map = mip_map;
+            }
+            interior_mip_map(&self.nodes, node.mask, &self.nodes[start..end])
+        };
+        rebuilt.insert((node.mask, node.child_index_is_leaf), mip_map);
+        mip_map
+    }
+
+    /// Rebuilds `nodes` and `leaves` from the root, dropping data left unreachable by
+    /// edits and sharing identical subtrees again.
+    ///
+    /// Returns the number of bytes saved by sharing.
+    pub fn compact(&mut self) -> usize {
+        self.generation = next_generation();
+        let mut compacted = Compacted {
+            node_hash: NodeHash::default(),
+            remapped: FxHashMap::default(),
+            nodes: vec![Node::default()],
+            leaves: Leaves::for_materials(self.materials.len()),
+            saved_bytes: 0,
+        };
+        let root = compacted.node(self, self.nodes[0]);
+        compacted.nodes[0] = root;
+        self.nodes = compacted.nodes.into();
+        self.leaves = compacted.leaves;
+        compacted.saved_bytes
+    }
+}
+
+struct Compacted {
+    node_hash: NodeHash,
+    // Maps `child_index_is_leaf` of the edited tree to the compacted tree.
+    remapped: FxHashMap<u32, u32>,
+    nodes: Vec<Node>,
+    leaves: Leaves,
+    saved_bytes: usize,
+}
+
+impl Compacted {
+    fn node(&mut self, tree: &VoxelTree, node: Node) -> Node {
+        if node.mask == 0 {
+            return Node::default();
+        }
+        if !node.is_loaded() {
+            return node;
+        }
+        if let Some(&child_index_is_leaf) = self.remapped.get(&node.child_index_is_leaf) {
+            return Node {
+                child_index_is_leaf,
+                ..node
+            };
+        }
+
+        let start = node.child_index();
+        let end = start + node.mask.count_ones() as usize;
+        let child_index_is_leaf = if node.is_leaf() {
+            let leaf_index = self.node_hash.leaves(
+                tree.leaves.to_vec(start..end),
+                &mut self.leaves,
+                &mut self.saved_bytes,
+            );
+            (leaf_index << 1) | 1
+        } else {
+            let children = tree.nodes[start..end]
+                .iter()
+                .map(|child| self.node(tree, *child))
+                .collect::<Vec<_>>();
+            let child_index =
+                self.node_hash
+                    .children(&children, &mut self.nodes, &mut self.saved_bytes);
+            child_index << 1
+        };
+        self.remapped
+            .insert(node.child_index_is_leaf, child_index_is_leaf);
+        Node {
+            child_index_is_leaf,
+            ..node
+        }
+    }
+}
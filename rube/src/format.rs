@@ -0,0 +1,664 @@
+// On-disk layout of a `VoxelTree`:
+//
+// [  4   |    2    |    n     |         ...         ]
+// [ RUBE | version | header   | compressed payload  ]
+//
+// `version` is a little endian `u16` and determines how both the header and the
+// payload are decoded. The header is postcard encoded and the payload is a postcard
+// encoded `VoxelTree` compressed with `Header::compression`, except for
+// `Compression::Mapped`, see `mod mapped`, and `Header::paged`, see `page`.
+//
+// Files written before the header existed are a bare bzip2 compressed postcard
+// `VoxelTree` and are loaded as version 0.
+
+use crate::material::Material;
+use crate::tree::{Leaves, MAX_EXP, MIN_EXP, Node, VoxelTree};
+use crate::validate::ValidationError;
+use std::io::{Read, Write};
+use std::path::Path;
+
+pub const MAGIC: [u8; 4] = *b"RUBE";
+pub const VERSION: u16 = 7;
+
+/// An lz4 block can expand to at most about 255 times its size, since each extra
+/// match length byte adds at most 255 bytes.
+const LZ4_MAX_RATIO: usize = 255;
+
+#[derive(Debug)]
+pub enum LoadError {
+    /// The file could not be read.
+    Io(std::io::Error),
+    /// The payload is not a valid compressed stream.
+    Decompress(std::io::Error),
+    /// The header or payload does not match the expected layout.
+    Decode(postcard::Error),
+    /// The header ended before the format version.
+    TruncatedHeader,
+    /// A mapped payload ended before the end of its arrays.
+    TruncatedPayload,
+    /// The file was written by a newer version of the format.
+    UnsupportedVersion(u16),
+    /// The tree depth is odd or outside of `MIN_EXP..=MAX_EXP`.
+    UnsupportedExp(u32),
+    /// The page directory of a paged file has an invalid page size, points outside
+    /// of its nodes or doesn't match its page roots.
+    InvalidDirectory,
+    /// A page of a paged file doesn't form a well formed subtree beneath its roots.
+    InvalidPage,
+    /// The decoded tree fails `VoxelTree::validate`.
+    Invalid(ValidationError),
+    /// The tree was edited, compacted or replaced while paged, see `page::Pager`.
+    StaleTree,
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "failed to read tree: {err}"),
+            Self::Decompress(err) => write!(f, "failed to decompress tree: {err}"),
+            Self::Decode(err) => write!(f, "failed to decode tree: {err}"),
+            Self::TruncatedHeader => write!(f, "truncated header"),
+            Self::TruncatedPayload => write!(f, "truncated payload"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "file format version {version} is newer than the supported version {VERSION}"
+            ),
+            Self::UnsupportedExp(exp) => write!(
+                f,
+                "tree depth 2^{exp} is not an even exponent in {MIN_EXP}..={MAX_EXP}"
+            ),
+            Self::InvalidDirectory => write!(f, "invalid page directory"),
+            Self::InvalidPage => write!(f, "invalid page"),
+            Self::Invalid(err) => write!(f, "invalid tree: {err}"),
+            Self::StaleTree => write!(f, "the paged tree was edited, compacted or replaced"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) | Self::Decompress(err) => Some(err),
+            Self::Decode(err) => Some(err),
+            Self::Invalid(err) => Some(err),
+            Self::TruncatedHeader
+            | Self::TruncatedPayload
+            | Self::UnsupportedVersion(_)
+            | Self::UnsupportedExp(_)
+            | Self::InvalidDirectory
+            | Self::InvalidPage
+            | Self::StaleTree => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for LoadError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<postcard::Error> for LoadError {
+    fn from(err: postcard::Error) -> Self {
+        Self::Decode(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Compression {
+    None,
+    Bzip2,
+    /// Uncompressed arrays laid out to be memory mapped by `VoxelTree::open`.
+    Mapped,
+    /// Faster to decompress than `Bzip2` at a somewhat larger size.
+    Zstd,
+    /// The fastest to decompress, at a larger size than `Zstd`.
+    Lz4,
+}
+
+impl Compression {
+    /// `Mapped` payloads are not a postcard stream and are passed through unchanged.
+    pub fn compress(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Self::None | Self::Mapped => bytes.to_vec(),
+            Self::Bzip2 => {
+                let mut encoder =
+                    bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::best());
+                encoder.write_all(bytes).unwrap();
+                encoder.finish().unwrap()
+            }
+            Self::Zstd => ruzstd::encoding::compress_to_vec(
+                bytes,
+                ruzstd::encoding::CompressionLevel::Fastest,
+            ),
+            Self::Lz4 => lz4_flex::compress_prepend_size(bytes),
+        }
+    }
+
+    pub fn decompress(self, bytes: &[u8]) -> Result<Vec<u8>, LoadError> {
+        match self {
+            Self::None | Self::Mapped => Ok(bytes.to_vec()),
+            Self::Bzip2 => {
+                let mut decoder = bzip2::read::BzDecoder::new(bytes);
+                let mut decompressed = Vec::with_capacity(bytes.len());
+                decoder
+                    .read_to_end(&mut decompressed)
+                    .map_err(LoadError::Decompress)?;
+                Ok(decompressed)
+            }
+            Self::Zstd => {
+                let mut decoder = ruzstd::decoding::StreamingDecoder::new(bytes)
+                    .map_err(|err| LoadError::Decompress(std::io::Error::other(err)))?;
+                let mut decompressed = Vec::with_capacity(bytes.len());
+                decoder
+                    .read_to_end(&mut decompressed)
+                    .map_err(LoadError::Decompress)?;
+                Ok(decompressed)
+            }
+            Self::Lz4 => {
+                let (size, block) = lz4_flex::block::uncompressed_size(bytes)
+                    .map_err(|err| LoadError::Decompress(std::io::Error::other(err)))?;
+                // The prefix is allocated up front, so reject sizes no block of this
+                // length could expand to instead of trusting a corrupt prefix.
+                if size > block.len().saturating_mul(LZ4_MAX_RATIO) {
+                    return Err(LoadError::Decompress(std::io::Error::other(format!(
+                        "lz4 size prefix {size} exceeds the maximum for a {} byte block",
+                        block.len()
+                    ))));
+                }
+                lz4_flex::decompress(block, size)
+                    .map_err(|err| LoadError::Decompress(std::io::Error::other(err)))
+            }
+        }
+    }
+
+    /// Conventional file extension, e.g. `bin.bz2`.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::None | Self::Mapped => "bin",
+            Self::Bzip2 => "bin.bz2",
+            Self::Zstd => "bin.zst",
+            Self::Lz4 => "bin.lz4",
+        }
+    }
+}
+
+/// Describes where a tree came from.
+#[derive(Debug, Default, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SourceMetadata {
+    /// File the tree was generated from, e.g. `castle.vox`.
+    pub path: String,
+    /// Name and version of the tool that generated the tree.
+    pub generator: String,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Header {
+    #[serde(skip)]
+    pub version: u16,
+    pub compression: Compression,
+    pub exp: u32,
+    pub voxels: u64,
+    /// Whether leaves store 16 bit material ids, see `Leaves`.
+    pub wide_leaves: bool,
+    /// Whether the payload is split into separately compressed pages, see `page`.
+    pub paged: bool,
+    pub source: SourceMetadata,
+}
+
+impl Header {
+    pub fn new(tree: &VoxelTree, compression: Compression, source: SourceMetadata) -> Self {
+        Self {
+            version: VERSION,
+            compression,
+            exp: tree.exp,
+            voxels: tree.voxel_count(),
+            wide_leaves: tree.leaves.is_wide(),
+            paged: false,
+            source,
+        }
+    }
+
+    /// Reads the header at the start of `bytes` and returns it along with the
+    /// remaining payload.
+    ///
+    /// Returns `None` for legacy files without a header.
+    pub fn read(bytes: &[u8]) -> Result<Option<(Self, &[u8])>, LoadError> {
+        let Some(rest) = bytes.strip_prefix(&MAGIC) else {
+            return Ok(None);
+        };
+        let (version, rest) = rest
+            .split_first_chunk::<2>()
+            .ok_or(LoadError::TruncatedHeader)?;
+        let version = u16::from_le_bytes(*version);
+        if version > VERSION {
+            return Err(LoadError::UnsupportedVersion(version));
+        }
+        let (mut header, payload) = if version < 4 {
+            let (header, payload) = postcard::take_from_bytes::<v3::Header>(rest)?;
+            (v3::migrate_header(header), payload)
+        } else if version < 7 {
+            let (header, payload) = postcard::take_from_bytes::<v6::Header>(rest)?;
+            (v6::migrate_header(header), payload)
+        } else {
+            postcard::take_from_bytes::<Self>(rest)?
+        };
+        header.version = version;
+        Ok(Some((header, payload)))
+    }
+
+    /// Reads only the header of the file at `path`.
+    pub fn read_file(path: impl AsRef<Path>) -> Result<Option<Self>, LoadError> {
+        let bytes = std::fs::read(path)?;
+        Ok(Self::read(&bytes)?.map(|(header, _)| header))
+    }
+
+    pub fn write(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&self.version.to_le_bytes());
+        bytes.extend_from_slice(&postcard::to_allocvec(self).unwrap());
+    }
+}
+
+pub fn encode(tree: &VoxelTree, compression: Compression, source: SourceMetadata) -> Vec<u8> {
+    let header = Header::new(tree, compression, source);
+    let mut bytes = Vec::new();
+    header.write(&mut bytes);
+    if compression == Compression::Mapped {
+        mapped::write(tree, &mut bytes);
+    } else {
+        bytes.extend_from_slice(&compression.compress(&postcard::to_allocvec(tree).unwrap()));
+    }
+    bytes
+}
+
+pub fn decode(bytes: &[u8]) -> Result<VoxelTree, LoadError> {
+    let tree = match Header::read(bytes)? {
+        Some((header, payload)) if header.paged => crate::page::decode(&header, payload)?,
+        Some((header, payload)) if header.compression == Compression::Mapped => {
+            mapped::decode(bytes, bytes.len() - payload.len(), header.wide_leaves)?
+        }
+        Some((header, payload)) => {
+            migrate(header.version, &header.compression.decompress(payload)?)?
+        }
+        None => migrate(0, &Compression::Bzip2.decompress(bytes)?)?,
+    };
+    check(tree)
+}
+
+/// Memory maps `Compression::Mapped` files and decodes any other file, see
+/// `VoxelTree::open`.
+///
+/// # Safety
+///
+/// See `VoxelTree::open`.
+#[cfg(not(target_arch = "wasm32"))]
+pub unsafe fn open(path: impl AsRef<Path>) -> Result<VoxelTree, LoadError> {
+    let file = std::fs::File::open(path)?;
+    // SAFETY: The caller keeps the file unchanged while the tree is alive.
+    let map = unsafe { memmap2::Mmap::map(&file)? };
+    let (offset, wide_leaves) = match Header::read(&map)? {
+        // The arrays are stored little endian and can only be used in place on
+        // little endian targets.
+        Some((header, payload))
+            if header.compression == Compression::Mapped && cfg!(target_endian = "little") =>
+        {
+            (map.len() - payload.len(), header.wide_leaves)
+        }
+        _ => return decode(&map),
+    };
+    check(mapped::map(std::sync::Arc::new(map), offset, wide_leaves)?)
+}
+
+/// # Safety
+///
+/// Files are read instead of mapped on this target, so this is always safe. It is
+/// only unsafe to match the other targets.
+#[cfg(target_arch = "wasm32")]
+pub unsafe fn open(path: impl AsRef<Path>) -> Result<VoxelTree, LoadError> {
+    decode(&std::fs::read(path)?)
+}
+
+/// Runs `VoxelTree::validate` on a decoded tree, so corrupt files fail to load instead
+/// of panicking during traversal.
+pub(crate) fn check(tree: VoxelTree) -> Result<VoxelTree, LoadError> {
+    match tree.validate() {
+        Ok(()) => Ok(tree),
+        Err(ValidationError::UnsupportedExp(exp)) => Err(LoadError::UnsupportedExp(exp)),
+        Err(err) => Err(LoadError::Invalid(err)),
+    }
+}
+
+/// Decodes a decompressed payload written with format `version` into the current
+/// `VoxelTree` layout.
+///
+/// When the layout of `VoxelTree` or `Node` changes, bump `VERSION`, keep a copy of
+/// the old types here and convert them in a new match arm.
+fn migrate(version: u16, payload: &[u8]) -> Result<VoxelTree, LoadError> {
+    match version {
+        // Version 0 only lacked the header, the payload is unchanged.
+        0 | 1 => Ok(v3::migrate(v2::migrate(v1::migrate(
+            postcard::from_bytes(payload)?,
+        )?))),
+        2 => Ok(v3::migrate(v2::migrate(postcard::from_bytes(payload)?))),
+        3 => Ok(v3::migrate(postcard::from_bytes(payload)?)),
+        // Versions 5 and 6 only added `Compression` variants and version 7 only added
+        // `Header::paged`, the postcard payload is unchanged.
+        4..=7 => Ok(postcard::from_bytes(payload)?),
+        _ => Err(LoadError::UnsupportedVersion(version)),
+    }
+}
+
+// Plain trees without `Node::attribute_offset`.
+mod v1 {
+    use super::ValidationError;
+    use fxhash::FxHashMap;
+
+    #[derive(serde::Deserialize)]
+    pub struct VoxelTree {
+        nodes: Vec<Node>,
+        leaves: Vec<u8>,
+        palette: Vec<u32>,
+        exp: u32,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Node {
+        child_index_is_leaf: u32,
+        mask: u64,
+        mip_map: u32,
+    }
+
+    pub fn migrate(tree: VoxelTree) -> Result<super::v2::VoxelTree, super::LoadError> {
+        if !tree.exp.is_multiple_of(2) || !(super::MIN_EXP..=super::MAX_EXP).contains(&tree.exp) {
+            return Err(super::LoadError::UnsupportedExp(tree.exp));
+        }
+        let mut nodes = tree
+            .nodes
+            .into_iter()
+            .map(|node| super::Node {
+                child_index_is_leaf: node.child_index_is_leaf,
+                mask: node.mask,
+                mip_map: node.mip_map,
+                attribute_offset: 0,
+            })
+            .collect::<Vec<_>>();
+        if nodes.is_empty() {
+            return Err(super::LoadError::Invalid(ValidationError::MissingRoot));
+        }
+        let leaf_depth = tree.exp / 2 - 1;
+        assign_attribute_offsets(&mut nodes, 0, 0, leaf_depth, &mut FxHashMap::default())
+            .map_err(super::LoadError::Invalid)?;
+        Ok(super::v2::VoxelTree {
+            nodes,
+            leaves: tree.leaves,
+            palette: tree.palette,
+            exp: tree.exp,
+        })
+    }
+
+    // Returns the number of voxels beneath `nodes[index]` at `depth`. Child ranges are
+    // checked before they are followed and the recursion ends at `leaf_depth`, the
+    // rest is left to `VoxelTree::validate`. `assigned` is keyed by mask and
+    // `child_index_is_leaf`, so shared children are only visited once.
+    fn assign_attribute_offsets(
+        nodes: &mut [super::Node],
+        index: usize,
+        depth: u32,
+        leaf_depth: u32,
+        assigned: &mut FxHashMap<(u64, u32), u32>,
+    ) -> Result<u32, ValidationError> {
+        let node = nodes[index];
+        if node.mask == 0 {
+            return Ok(0);
+        }
+        if node.is_leaf() != (depth == leaf_depth) {
+            return Err(ValidationError::InconsistentDepth { node: index, depth });
+        }
+        if node.is_leaf() {
+            return Ok(node.mask.count_ones());
+        }
+        if let Some(&voxels) = assigned.get(&(node.mask, node.child_index_is_leaf)) {
+            return Ok(voxels);
+        }
+        let start = node.child_index();
+        let end = start + node.mask.count_ones() as usize;
+        if end > nodes.len() {
+            return Err(ValidationError::ChildrenOutOfBounds { node: index });
+        }
+        let mut voxels = 0u32;
+        for child in start..end {
+            nodes[child].attribute_offset = voxels;
+            let child_voxels =
+                assign_attribute_offsets(nodes, child, depth + 1, leaf_depth, assigned)?;
+            voxels = voxels.saturating_add(child_voxels);
+        }
+        assigned.insert((node.mask, node.child_index_is_leaf), voxels);
+        Ok(voxels)
+    }
+}
+
+// Trees with a color palette instead of a material table.
+mod v2 {
+    #[derive(serde::Deserialize)]
+    pub struct VoxelTree {
+        pub nodes: Vec<super::Node>,
+        pub leaves: Vec<u8>,
+        pub palette: Vec<u32>,
+        pub exp: u32,
+    }
+
+    pub fn migrate(tree: VoxelTree) -> super::v3::VoxelTree {
+        super::v3::VoxelTree {
+            nodes: tree.nodes,
+            leaves: tree.leaves,
+            materials: tree
+                .palette
+                .into_iter()
+                .map(super::Material::diffuse)
+                .collect(),
+            exp: tree.exp,
+        }
+    }
+}
+
+// Trees and headers with 8 bit material ids only.
+mod v3 {
+    #[derive(serde::Deserialize)]
+    pub struct Header {
+        compression: super::Compression,
+        exp: u32,
+        voxels: u64,
+        source: super::SourceMetadata,
+    }
+
+    pub fn migrate_header(header: Header) -> super::Header {
+        super::Header {
+            version: 0,
+            compression: header.compression,
+            exp: header.exp,
+            voxels: header.voxels,
+            wide_leaves: false,
+            paged: false,
+            source: header.source,
+        }
+    }
+
+    #[derive(serde::Deserialize)]
+    pub struct VoxelTree {
+        pub nodes: Vec<super::Node>,
+        pub leaves: Vec<u8>,
+        pub materials: Vec<super::Material>,
+        pub exp: u32,
+    }
+
+    pub fn migrate(tree: VoxelTree) -> super::VoxelTree {
+        super::VoxelTree::new(
+            tree.nodes.into(),
+            super::Leaves::Narrow(tree.leaves.into()),
+            tree.materials,
+            tree.exp,
+        )
+    }
+}
+
+// Headers without `paged`.
+mod v6 {
+    #[derive(serde::Deserialize)]
+    pub struct Header {
+        compression: super::Compression,
+        exp: u32,
+        voxels: u64,
+        wide_leaves: bool,
+        source: super::SourceMetadata,
+    }
+
+    pub fn migrate_header(header: Header) -> super::Header {
+        super::Header {
+            version: 0,
+            compression: header.compression,
+            exp: header.exp,
+            voxels: header.voxels,
+            wide_leaves: header.wide_leaves,
+            paged: false,
+            source: header.source,
+        }
+    }
+}
+
+// Layout of `Compression::Mapped` payloads, with every integer little endian:
+//
+// [    n     |  0..8   |    24 * n    |  1 or 2 * n  ]
+// [ sections | padding |    nodes     |    leaves    ]
+//
+// `sections` is postcard encoded. The padding aligns `nodes` to 8 bytes from the start
+// of the file, so that on little endian targets both arrays can be used in place once
+// the file is memory mapped. Each node is laid out like `Node` in memory, with 4 bytes
+// of zeroed padding after `child_index_is_leaf`.
+mod mapped {
+    use super::{LoadError, Material, Node, VoxelTree};
+    use crate::storage::Storage;
+    use crate::tree::Leaves;
+    use std::ops::Range;
+
+    const NODE_SIZE: usize = 24;
+    const _: () = assert!(
+        size_of::<Node>() == NODE_SIZE
+            && align_of::<Node>() == 8
+            && std::mem::offset_of!(Node, child_index_is_leaf) == 0
+            && std::mem::offset_of!(Node, mask) == 8
+            && std::mem::offset_of!(Node, mip_map) == 16
+            && std::mem::offset_of!(Node, attribute_offset) == 20
+    );
+
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct Sections {
+        exp: u32,
+        materials: Vec<Material>,
+        nodes: u64,
+        leaves: u64,
+    }
+
+    pub fn write(tree: &VoxelTree, bytes: &mut Vec<u8>) {
+        let sections = Sections {
+            exp: tree.exp,
+            materials: tree.materials.clone(),
+            nodes: tree.nodes.len() as u64,
+            leaves: tree.leaves.len() as u64,
+        };
+        bytes.extend_from_slice(&postcard::to_allocvec(&sections).unwrap());
+        bytes.resize(bytes.len().next_multiple_of(align_of::<Node>()), 0);
+        bytes.reserve(tree.nodes.len() * NODE_SIZE + tree.leaves.len() * tree.leaves.id_size());
+        for node in tree.nodes.iter() {
+            bytes.extend_from_slice(&node.child_index_is_leaf.to_le_bytes());
+            bytes.extend_from_slice(&[0; 4]);
+            bytes.extend_from_slice(&node.mask.to_le_bytes());
+            bytes.extend_from_slice(&node.mip_map.to_le_bytes());
+            bytes.extend_from_slice(&node.attribute_offset.to_le_bytes());
+        }
+        match &tree.leaves {
+            Leaves::Narrow(leaves) => bytes.extend_from_slice(leaves),
+            Leaves::Wide(leaves) => {
+                for id in leaves.iter() {
+                    bytes.extend_from_slice(&id.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    // Returns the sections and the byte ranges of `nodes` and `leaves` in `bytes`, whose
+    // payload starts at `offset`.
+    fn read_sections(
+        bytes: &[u8],
+        offset: usize,
+        wide_leaves: bool,
+    ) -> Result<(Sections, Range<usize>, Range<usize>), LoadError> {
+        let (sections, rest) = postcard::take_from_bytes::<Sections>(&bytes[offset..])?;
+        let id_size = if wide_leaves { 2 } else { 1 };
+        let nodes_start = (bytes.len() - rest.len()).next_multiple_of(align_of::<Node>());
+        let nodes_end = usize::try_from(sections.nodes)
+            .ok()
+            .and_then(|nodes| nodes.checked_mul(NODE_SIZE)?.checked_add(nodes_start))
+            .ok_or(LoadError::TruncatedPayload)?;
+        let leaves_end = usize::try_from(sections.leaves)
+            .ok()
+            .and_then(|leaves| leaves.checked_mul(id_size)?.checked_add(nodes_end))
+            .ok_or(LoadError::TruncatedPayload)?;
+        if leaves_end > bytes.len() {
+            return Err(LoadError::TruncatedPayload);
+        }
+        Ok((sections, nodes_start..nodes_end, nodes_end..leaves_end))
+    }
+
+    /// Copies the arrays out of `bytes`.
+    pub fn decode(bytes: &[u8], offset: usize, wide_leaves: bool) -> Result<VoxelTree, LoadError> {
+        let (sections, nodes, leaves) = read_sections(bytes, offset, wide_leaves)?;
+        let u32_at =
+            |chunk: &[u8], i: usize| u32::from_le_bytes(chunk[i..i + 4].try_into().unwrap());
+        let nodes = bytes[nodes]
+            .chunks_exact(NODE_SIZE)
+            .map(|chunk| Node {
+                child_index_is_leaf: u32_at(chunk, 0),
+                mask: u64::from_le_bytes(chunk[8..16].try_into().unwrap()),
+                mip_map: u32_at(chunk, 16),
+                attribute_offset: u32_at(chunk, 20),
+            })
+            .collect::<Vec<_>>();
+        let leaves = if wide_leaves {
+            let leaves = bytes[leaves]
+                .chunks_exact(2)
+                .map(|id| u16::from_le_bytes([id[0], id[1]]))
+                .collect::<Vec<_>>();
+            Leaves::Wide(leaves.into())
+        } else {
+            Leaves::Narrow(bytes[leaves].to_vec().into())
+        };
+        Ok(VoxelTree::new(
+            nodes.into(),
+            leaves,
+            sections.materials,
+            sections.exp,
+        ))
+    }
+
+    /// Borrows the arrays from `map` without copying them.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn map(
+        map: std::sync::Arc<memmap2::Mmap>,
+        offset: usize,
+        wide_leaves: bool,
+    ) -> Result<VoxelTree, LoadError> {
+        let (sections, nodes, leaves) = read_sections(&map, offset, wide_leaves)?;
+        let leaves = if wide_leaves {
+            Leaves::Wide(Storage::mapped(map.clone(), leaves.start, leaves.len() / 2))
+        } else {
+            Leaves::Narrow(Storage::mapped(map.clone(), leaves.start, leaves.len()))
+        };
+        Ok(VoxelTree::new(
+            Storage::mapped(map, nodes.start, nodes.len() / NODE_SIZE),
+            leaves,
+            sections.materials,
+            sections.exp,
+        ))
+    }
+}
@@ -17,6 +17,9 @@ use std::f32::consts::TAU;
 pub const SKY_COLOR: Vec3 = Vec3::new(0.246, 0.624, 0.838);
 
 pub struct IndirectPass {
+    /// Shades hits by the number of nodes read while tracing them instead of their
+    /// material.
+    pub show_reads: bool,
     last_visible_voxels: FxHashMap<usize, VoxelData>,
     visible_voxels: FxHashMap<usize, VoxelData>,
     color_buffer: Vec<PackedColorData>,
@@ -26,6 +29,7 @@ pub struct IndirectPass {
 impl IndirectPass {
     pub fn new(width: usize, height: usize) -> Self {
         Self {
+            show_reads: false,
             last_visible_voxels: FxHashMap::default(),
             visible_voxels: FxHashMap::default(),
             color_buffer: vec![PackedColorData::default(); width * height],
@@ -36,14 +40,14 @@ impl IndirectPass {
 
 #[derive(Clone, Copy)]
 struct PackedColorData {
-    leaf_index_and_escape: u32,
+    attribute_index_and_escape: u32,
     color: Vec3,
 }
 
 impl Default for PackedColorData {
     fn default() -> Self {
         Self {
-            leaf_index_and_escape: 1,
+            attribute_index_and_escape: 1,
             color: Vec3::ZERO,
         }
     }
@@ -70,7 +74,7 @@ pub struct DirectionalLight {
 pub fn indirect_pass(
     scene: &Scene,
     march_pass: &MarchPass,
-    _indirect_pass: &mut IndirectPass,
+    indirect_pass: &mut IndirectPass,
     pixels: &mut [u32],
 ) {
     // let tree = &scene.tree;
@@ -83,8 +87,7 @@ pub fn indirect_pass(
     //     &mut indirect_pass.last_visible_voxels,
     // );
 
-    // // NOTE: currently hardcoded based on the voxelization code
-    // let scale_exp = 11;
+    // let scale_exp = tree.leaf_scale_exp();
     // let size_bits = 1u32 << scale_exp;
     // let size = f32::from_bits(0x3f800000 | size_bits) - 1.0;
     // let half_size = size * 0.5;
@@ -96,7 +99,7 @@ pub fn indirect_pass(
     //     for hit in march_pass.hits.iter().filter(|h| !h.escaped()) {
     //         indirect_pass
     //             .visible_voxels
-    //             .entry(hit.leaf_index())
+    //             .entry(hit.attribute_index())
     //             .or_insert_with(|| VoxelData {
     //                 color: Vec3::ZERO,
     //                 accumulator: Vec3::ZERO,
@@ -166,7 +169,7 @@ pub fn indirect_pass(
     //         .for_each(|(i, (data, hit))| {
     //             data.color =
     //                 voxel_indirect(tree, hit, pcg(i as u32 ^ pcg(indirect_pass.frame)) as u64);
-    //             data.leaf_index_and_escape = (hit.leaf_index() as u32) << 1;
+    //             data.attribute_index_and_escape = (hit.attribute_index() as u32) << 1;
     //         });
     // }
 
@@ -175,11 +178,11 @@ pub fn indirect_pass(
     //     for data in indirect_pass
     //         .color_buffer
     //         .iter()
-    //         .filter(|d| (d.leaf_index_and_escape & 1) == 0)
+    //         .filter(|d| (d.attribute_index_and_escape & 1) == 0)
     //     {
     //         let voxel_data = indirect_pass
     //             .visible_voxels
-    //             .get_mut(&((data.leaf_index_and_escape as usize) >> 1))
+    //             .get_mut(&((data.attribute_index_and_escape as usize) >> 1))
     //             .unwrap();
     //         voxel_data.accumulator += data.color;
     //         voxel_data.samples += 1;
@@ -201,19 +204,17 @@ pub fn indirect_pass(
     {
         profiling::scope!("write pixels");
         for (pixel, hit) in pixels.iter_mut().zip(march_pass.hits.iter()) {
-            if !hit.escaped() {
-                let albedo = Vec3::splat(hit.reads as f32) / 200.0;
-                // let data = &indirect_pass.visible_voxels[&hit.leaf_index()];
-                // let albedo = if hit.mip_map != 0 {
-                //     VoxelTree::unpack_srgb_linear(hit.mip_map)
-                // } else {
-                //     tree.linear_rgb(tree.leaves[hit.leaf_index()] as usize)
-                // };
-                // let color = if data.occluded {
-                //     albedo * 0.2
-                // } else {
-                let color = albedo;
-                // };
+            if !hit.missed() {
+                let color = if indirect_pass.show_reads {
+                    Vec3::splat(hit.reads as f32) / 200.0
+                } else if hit.is_coarse() {
+                    // Coarse hits have no leaf, only the average color beneath them.
+                    VoxelTree::unpack_srgb_linear(hit.mip_map)
+                } else {
+                    let tree = &scene.tree;
+                    let material = &tree.materials[tree.leaves.get(hit.leaf_index()) as usize];
+                    material.linear_albedo() + material.linear_emission()
+                };
                 *pixel = VoxelTree::pack_linear_rgb(color);
             } else {
                 // *pixel = VoxelTree::pack_linear_rgb(SKY_COLOR);
@@ -244,16 +245,16 @@ fn voxel_indirect(tree: &VoxelTree, hit: &PackedHitInfo, seed: u64) -> Vec3 {
         // We should also divide the result by the PDF (1 / (2 * M_PI)), but we can do this after
         let origin = hit.position + hit.normal() * 1e-4;
         let sample_hit = Ray::new(origin, local_sample).cast(tree);
-        if !sample_hit.escaped() {
+        if !sample_hit.missed() {
             let dist = hit.position.distance(sample_hit.position);
             let attenuation = (1.0 - (dist / 0.01)).max(0.0);
             occlusion += attenuation * r1;
         }
     }
 
-    let albedo = tree.linear_rgb(tree.leaves[hit.leaf_index()] as usize);
+    let material = &tree.materials[tree.leaves.get(hit.leaf_index()) as usize];
     let ao = 1.0 - (occlusion / samples as f32);
-    albedo * ao
+    material.linear_albedo() * ao + material.linear_emission()
 }
 
 fn normal_coordinate_system(n: Vec3) -> (Vec3, Vec3) {
@@ -1,4 +1,5 @@
 use crate::bench::Benchmarker;
+use crate::format::LoadError;
 use crate::indirect::IndirectPass;
 use crate::march::MarchPass;
 use crate::scene::Scene;
@@ -7,12 +8,23 @@ use std::{collections::VecDeque, path::Path};
 
 mod bench;
 mod camera;
+pub mod csg;
+mod edit;
+pub mod format;
 pub mod indirect;
 pub mod map;
 pub mod march;
-mod ray;
+pub mod material;
+mod packet;
+pub mod page;
+pub mod patch;
+mod query;
+pub mod ray;
 pub mod scene;
+pub mod storage;
+pub mod transform;
 pub mod tree;
+pub mod validate;
 
 pub struct World {
     sliding_fps: VecDeque<f32>,
@@ -23,19 +35,25 @@ pub struct World {
     bencher: Benchmarker,
 }
 
-pub fn create_world_from_tree(
+/// # Safety
+///
+/// The tree file must not be modified while the world is alive, see
+/// `Scene::from_tree`.
+pub unsafe fn create_world_from_tree(
     path: impl AsRef<Path>,
-) -> impl FnOnce(&Window, usize, usize) -> World {
-    |window, width, height| {
+) -> Result<impl FnOnce(&Window, usize, usize) -> World, LoadError> {
+    // SAFETY: Forwarded to the caller.
+    let scene = unsafe { Scene::from_tree(path)? };
+    Ok(move |window: &Window, width, height| {
         window.set_title("RUBE");
         World {
             sliding_fps: VecDeque::with_capacity(100),
-            scene: Scene::from_tree(path),
+            scene,
             march_pass: MarchPass::new(width, height),
             indirect_pass: IndirectPass::new(width, height),
             bencher: bench::bench1(),
         }
-    }
+    })
 }
 
 #[unsafe(no_mangle)]
@@ -61,6 +79,9 @@ pub fn handle_input(
                         KeyCode::Escape => {
                             std::process::exit(0);
                         }
+                        KeyCode::KeyH => {
+                            world.indirect_pass.show_reads = !world.indirect_pass.show_reads;
+                        }
                         KeyCode::KeyP => {
                             println!("{:#?}", world.scene.camera);
                             // println!(
@@ -114,7 +135,9 @@ pub fn update_and_render(
         world.sliding_fps.iter().sum::<f32>() / world.sliding_fps.len() as f32
     ));
 
-    world.scene.update(delta);
+    if let Err(err) = world.scene.update(delta) {
+        eprintln!("[ERROR] failed to load page: {err}");
+    }
     #[cfg(feature = "bench")]
     {
         use glam::Vec3;
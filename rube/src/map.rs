@@ -1,17 +1,23 @@
+use crate::material::Material;
+use crate::tree::{MIN_EXP, MaterialId};
 use fxhash::FxHashMap;
 use glam::IVec3;
 
 #[derive(Debug)]
 pub struct VoxelMap {
     pub chunks: FxHashMap<IVec3, Brick>,
-    pub palette: [u32; 256],
+    /// Indexed by the material ids of `Brick`.
+    ///
+    /// Trees generated from maps with more than 256 materials store wide leaves, see
+    /// `Leaves`.
+    pub materials: Vec<Material>,
 }
 
 impl Default for VoxelMap {
     fn default() -> Self {
         Self {
             chunks: FxHashMap::default(),
-            palette: [u32::MAX; 256],
+            materials: vec![Material::default(); 256],
         }
     }
 }
@@ -53,6 +59,20 @@ impl VoxelMap {
         false
     }
 
+    /// Smallest tree depth whose root region contains every brick, assuming the map
+    /// has been shifted to positive coordinates.
+    pub fn tree_exp(&self) -> u32 {
+        let max = self
+            .chunks
+            .keys()
+            .fold(IVec3::ZERO, |acc, &k| acc.max(k + IVec3::ONE))
+            .max_element() as u32;
+        // Bricks are 8 voxels wide.
+        let extent = (max * 8).max(1);
+        let exp = extent.next_power_of_two().trailing_zeros();
+        (exp + exp % 2).max(MIN_EXP)
+    }
+
     pub fn shift_to_positive(&mut self) {
         if self.chunks.is_empty() {
             return;
@@ -70,14 +90,20 @@ impl VoxelMap {
     }
 }
 
+/// 8x8x8 voxels of a `VoxelMap`.
+///
+/// Material ids are stored in a single byte until a larger one is set, like `Leaves`.
+// Narrow bricks are kept inline since nearly every brick is one.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
-pub struct Brick {
-    pub data: [u8; 512],
+pub enum Brick {
+    Narrow([u8; 512]),
+    Wide(Box<[MaterialId; 512]>),
 }
 
 impl Default for Brick {
     fn default() -> Self {
-        Self { data: [0; 512] }
+        Self::Narrow([0; 512])
     }
 }
 
@@ -85,4 +111,38 @@ impl Brick {
     pub fn voxel_index(pos: IVec3) -> usize {
         (pos.x + (pos.z * 8) + (pos.y * 64)) as usize
     }
+
+    #[inline]
+    pub fn get(&self, index: usize) -> MaterialId {
+        match self {
+            Self::Narrow(data) => data[index] as MaterialId,
+            Self::Wide(data) => data[index],
+        }
+    }
+
+    pub fn set(&mut self, index: usize, material: MaterialId) {
+        match self {
+            Self::Narrow(data) => match u8::try_from(material) {
+                Ok(material) => data[index] = material,
+                Err(_) => {
+                    let mut wide = Box::new(data.map(MaterialId::from));
+                    wide[index] = material;
+                    *self = Self::Wide(wide);
+                }
+            },
+            Self::Wide(data) => data[index] = material,
+        }
+    }
+
+    /// Material ids in `Brick::voxel_index` order, `0` for empty voxels.
+    pub fn iter(&self) -> impl Iterator<Item = MaterialId> + '_ {
+        (0..512).map(|index| self.get(index))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Self::Narrow(data) => data.iter().all(|&material| material == 0),
+            Self::Wide(data) => data.iter().all(|&material| material == 0),
+        }
+    }
 }
@@ -2,7 +2,8 @@ use crate::ray::PackedHitInfo;
 use crate::ray::Ray;
 use crate::scene::Scene;
 use glam::{Mat4, Vec2, Vec3, Vec4Swizzles};
-use rayon::iter::{IndexedParallelIterator, IntoParallelRefMutIterator, ParallelIterator};
+use rayon::iter::{IndexedParallelIterator, ParallelIterator};
+use rayon::slice::ParallelSliceMut;
 
 pub struct MarchPass {
     pub hits: Vec<PackedHitInfo>,
@@ -23,22 +24,48 @@ pub fn march_pass(scene: &Scene, march_pass: &mut MarchPass, width: usize, heigh
         .projection_matrix(width, height)
         .mul_mat4(&scene.camera.view_matrix())
         .inverse();
+    // The far plane is flat, so pixels cover the same area anywhere on it.
+    let corner = unproject(0.0, 0.0, 1.0, width, height, &inv_proj_matrix);
+    let below = unproject(0.0, 1.0, 1.0, width, height, &inv_proj_matrix);
+    let footprint = corner.distance(below) * scene.camera.lod_bias.exp2();
+    let primary_ray = |px, py| {
+        primary_ray(
+            px,
+            py,
+            width,
+            height,
+            &inv_proj_matrix,
+            scene.camera.translation,
+            footprint,
+        )
+    };
+    // Neighboring rays are traced as packets of 2x2 pixels.
     march_pass
         .hits
-        .par_iter_mut()
+        .par_chunks_mut(width * 2)
         .enumerate()
-        .for_each(|(i, pixel)| {
-            let py = i / width;
-            let px = i % width;
-            let ray = primary_ray(
-                px,
-                py,
-                width,
-                height,
-                &inv_proj_matrix,
-                scene.camera.translation,
-            );
-            *pixel = ray.lod().cast(&scene.tree);
+        .for_each(|(i, rows)| {
+            let py = i * 2;
+            let (top, bottom) = rows.split_at_mut(width.min(rows.len()));
+            let mut px = 0;
+            while px + 1 < width && !bottom.is_empty() {
+                let rays = [
+                    primary_ray(px, py),
+                    primary_ray(px + 1, py),
+                    primary_ray(px, py + 1),
+                    primary_ray(px + 1, py + 1),
+                ];
+                let [a, b, c, d] = Ray::cast_packet(rays, &scene.tree);
+                (top[px], top[px + 1], bottom[px], bottom[px + 1]) = (a, b, c, d);
+                px += 2;
+            }
+            // Rays past the last full packet
+            for (px, pixel) in top.iter_mut().enumerate().skip(px) {
+                *pixel = primary_ray(px, py).cast(&scene.tree);
+            }
+            for (px, pixel) in bottom.iter_mut().enumerate().skip(px) {
+                *pixel = primary_ray(px, py + 1).cast(&scene.tree);
+            }
         });
 }
 
@@ -49,10 +76,31 @@ fn primary_ray(
     height: usize,
     inv_proj_matrix: &Mat4,
     origin: Vec3,
+    footprint: f32,
 ) -> Ray {
-    let uv = (Vec2::new(px as f32, py as f32) + Vec2::splat(0.5))
-        / Vec2::new(width as f32, height as f32);
+    let (px, py) = (px as f32, py as f32);
+    let near = unproject(px, py, 0.0, width, height, inv_proj_matrix);
+    let far = unproject(px, py, 1.0, width, height, inv_proj_matrix);
+    let far_distance = far.distance(origin);
+    // Clips the ray to the near and far planes, within a cone through the `footprint`
+    // of its pixel on the far plane.
+    Ray::new(origin, far.normalize())
+        .range(near.distance(origin), far_distance)
+        .lod(footprint / far_distance)
+}
+
+// Point seen through pixel `px, py` at normalized device depth `depth`, `0.0` on the
+// near plane and `1.0` on the far plane.
+fn unproject(
+    px: f32,
+    py: f32,
+    depth: f32,
+    width: usize,
+    height: usize,
+    inv_proj_matrix: &Mat4,
+) -> Vec3 {
+    let uv = (Vec2::new(px, py) + Vec2::splat(0.5)) / Vec2::new(width as f32, height as f32);
     let ndc = Vec2::new(uv.x * 2.0 - 1.0, -(uv.y * 2.0 - 1.0));
-    let far = inv_proj_matrix * ndc.extend(1.0).extend(1.0);
-    Ray::new(origin, (far.xyz() / far.w).normalize())
+    let point = inv_proj_matrix * ndc.extend(depth).extend(1.0);
+    point.xyz() / point.w
 }
@@ -0,0 +1,52 @@
+use crate::tree::VoxelTree;
+use glam::Vec3;
+
+/// Surface properties of a voxel, indexed by its leaf value.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Material {
+    /// Packed sRGB base color, see `VoxelTree::pack_srgb`.
+    pub albedo: u32,
+    /// Packed sRGB color of the emitted light.
+    pub emissive: u32,
+    /// Multiplier on `emissive`, `0` for surfaces that emit no light.
+    pub emissive_strength: f32,
+    pub roughness: f32,
+    pub metalness: f32,
+    /// `1` for fully opaque surfaces.
+    pub opacity: f32,
+    /// Index of refraction, only relevant when `opacity` is below `1`.
+    pub ior: f32,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self::diffuse(u32::MAX)
+    }
+}
+
+impl Material {
+    /// An opaque, rough and non emissive material.
+    pub fn diffuse(albedo: u32) -> Self {
+        Self {
+            albedo,
+            emissive: albedo,
+            emissive_strength: 0.0,
+            roughness: 1.0,
+            metalness: 0.0,
+            opacity: 1.0,
+            ior: 1.5,
+        }
+    }
+
+    pub fn linear_albedo(&self) -> Vec3 {
+        VoxelTree::unpack_srgb_linear(self.albedo)
+    }
+
+    /// Emitted radiance, already scaled by `emissive_strength`.
+    pub fn linear_emission(&self) -> Vec3 {
+        if self.emissive_strength == 0.0 {
+            return Vec3::ZERO;
+        }
+        VoxelTree::unpack_srgb_linear(self.emissive) * self.emissive_strength
+    }
+}
@@ -0,0 +1,420 @@
+// Packet traversal of coherent rays, see `Ray::cast_packet`.
+//
+// The rays of a packet share one node and descent stack while they agree on which
+// cells to descend into and which ancestors to return to, and do the rest of their
+// math in SIMD lanes. A ray that diverges from the packet continues on its own from
+// the same state with `Traversal::run`, so every ray takes exactly the steps of
+// `Ray::cast` and returns the same hit.
+
+use crate::ray::{
+    LOD_MIN_COVERAGE, MAX_ITERATIONS, Outcome, PackedHitInfo, Ray, Traversal, bit, cell_level,
+    cone_excess, leaf_hit, lod_hit, popcnt, unloaded_hit,
+};
+use crate::tree::{Node, VoxelTree};
+use bytemuck::cast;
+use glam::Vec3;
+use wide::{CmpEq, CmpGt, CmpLt, f32x4, i32x4, u32x4};
+
+pub(crate) const LANES: usize = 4;
+
+// A set of lanes as a bit mask.
+type LaneMask = u32;
+
+fn lanes(mask: LaneMask) -> impl Iterator<Item = usize> {
+    (0..LANES).filter(move |lane| mask & (1 << lane) != 0)
+}
+
+#[derive(Clone, Copy)]
+struct Vec3x4 {
+    x: f32x4,
+    y: f32x4,
+    z: f32x4,
+}
+
+impl Vec3x4 {
+    fn new(f: impl Fn(usize) -> Vec3) -> Self {
+        Self {
+            x: f32x4::new(std::array::from_fn(|lane| f(lane).x)),
+            y: f32x4::new(std::array::from_fn(|lane| f(lane).y)),
+            z: f32x4::new(std::array::from_fn(|lane| f(lane).z)),
+        }
+    }
+
+    fn get(&self, lane: usize) -> Vec3 {
+        Vec3::new(
+            self.x.as_array_ref()[lane],
+            self.y.as_array_ref()[lane],
+            self.z.as_array_ref()[lane],
+        )
+    }
+}
+
+pub(crate) fn cast_packet(tree: &VoxelTree, rays: [Ray; LANES]) -> [PackedHitInfo; LANES] {
+    // Rays only visit the same nodes if they agree on LOD.
+    let lod = |ray: &Ray| ray.cone_angle > 0.0;
+    if rays.iter().any(|ray| lod(ray) != lod(&rays[0])) {
+        return rays.map(|ray| ray.cast(tree));
+    }
+
+    let mut hits = [PackedHitInfo::default(); LANES];
+    let mut states = [None; LANES];
+    let mut start = None;
+    let mut active: LaneMask = 0;
+    for (lane, ray) in rays.into_iter().enumerate() {
+        match Traversal::new(tree, ray) {
+            Ok(traversal) => {
+                states[lane] = Some(Lane::new(&traversal));
+                start.get_or_insert(traversal);
+                active |= 1 << lane;
+            }
+            Err(hit) => hits[lane] = hit,
+        }
+    }
+    let Some(start) = start else {
+        return hits;
+    };
+    // A single ray is faster on its own.
+    if active.count_ones() < 2 {
+        hits[active.trailing_zeros() as usize] = finish(tree, start);
+        return hits;
+    }
+
+    // Every ray starts at the root, so the shared state is taken from any of them.
+    // Missing rays are filled in with another one and stay inactive.
+    let first = Lane::new(&start);
+    let packet = Packet {
+        tree,
+        lanes: states.map(|state| state.unwrap_or(first)),
+        reads: 0,
+        scale_exp: start.scale_exp,
+        node_index: start.node_index,
+        node: start.node,
+        attribute_index: start.attribute_index,
+        gs_stack: start.gs_stack,
+        attribute_stack: start.attribute_stack,
+        iteration: start.iteration,
+    };
+    packet.run(active, &mut hits);
+    hits
+}
+
+// The part of a `Traversal` that differs between rays when they start. Positions and
+// side distances are then tracked in SIMD lanes.
+#[derive(Clone, Copy)]
+struct Lane {
+    ray: Ray,
+    hit: PackedHitInfo,
+    pos: Vec3,
+    inv_dir: Vec3,
+    mirror_mask: usize,
+    initial_ray_d: f32,
+    max_dist: f32,
+}
+
+impl Lane {
+    fn new(traversal: &Traversal) -> Self {
+        Self {
+            ray: traversal.ray,
+            hit: traversal.hit,
+            pos: traversal.pos,
+            inv_dir: traversal.inv_dir,
+            mirror_mask: traversal.mirror_mask,
+            initial_ray_d: traversal.initial_ray_d,
+            max_dist: traversal.max_dist,
+        }
+    }
+}
+
+struct Packet<'a> {
+    tree: &'a VoxelTree,
+    lanes: [Lane; LANES],
+    // Nodes read since the rays started, on top of the reads of `lanes`.
+    reads: u32,
+    // Shared by all rays in the packet.
+    scale_exp: usize,
+    node_index: usize,
+    node: Node,
+    attribute_index: u32,
+    gs_stack: [usize; 11],
+    attribute_stack: [u32; 11],
+    iteration: usize,
+}
+
+impl Packet<'_> {
+    fn run(mut self, mut active: LaneMask, hits: &mut [PackedHitInfo; LANES]) {
+        let tree = self.tree;
+        let nodes: &[Node] = &tree.nodes;
+        let states = self.lanes;
+        let origin = Vec3x4::new(|lane| states[lane].ray.origin);
+        let abs_dir = Vec3x4::new(|lane| states[lane].ray.direction.abs());
+        let inv_dir = Vec3x4::new(|lane| states[lane].inv_dir);
+        let max_dist = f32x4::new(states.map(|state| state.max_dist));
+        let initial_ray_d = f32x4::new(states.map(|state| state.initial_ray_d));
+        let mirror_mask = u32x4::new(states.map(|state| state.mirror_mask as u32));
+        let lod = states[0].ray.cone_angle > 0.0;
+        let mut pos = Vec3x4::new(|lane| states[lane].pos);
+        let mut side_dist = Vec3x4::new(|_| Vec3::ZERO);
+
+        // Every `continue` restarts the current step, which is only done while the
+        // state of the remaining rays is the same as at its start.
+        'step: while self.iteration < MAX_ITERATIONS {
+            // A single ray is faster on its own.
+            if active.count_ones() < 2 {
+                for lane in lanes(active) {
+                    hits[lane] = finish(tree, self.split(lane, &pos, &side_dist));
+                }
+                return;
+            }
+
+            let mut child_index = child_indices(&pos, self.scale_exp, mirror_mask);
+            // Descend
+            loop {
+                let node = self.node;
+                let lead = active.trailing_zeros() as usize;
+                let children = child_index.to_array();
+                let descend = |child: u32| bit(node.mask, child as usize) && !node.is_leaf();
+                // Rays in other cells than the lead can only stay if neither descends.
+                let same_cell = child_index.cmp_eq(u32x4::splat(children[lead]));
+                let mut diverged = active & !(cast::<_, f32x4>(same_cell).move_mask() as u32);
+                if !descend(children[lead]) {
+                    diverged &= lanes(diverged)
+                        .filter(|&lane| descend(children[lane]))
+                        .fold(0, |mask, lane| mask | (1 << lane));
+                }
+                for lane in lanes(diverged) {
+                    hits[lane] = finish(tree, self.split(lane, &pos, &side_dist));
+                }
+                active &= !diverged;
+                if active.count_ones() < 2 {
+                    continue 'step;
+                }
+                if !descend(children[lead]) {
+                    break;
+                }
+
+                if !node.is_loaded() {
+                    let level = cell_level(tree, self.scale_exp + 2);
+                    for lane in lanes(active) {
+                        let direction = states[lane].ray.direction;
+                        let hit = self.hit(lane);
+                        hits[lane] = unloaded_hit(hit, pos.get(lane), direction, node, level);
+                    }
+                    return;
+                }
+                let node_index = node.child_index() + popcnt(node.mask, children[lead] as usize);
+
+                if lod {
+                    // mipmap early exit check with a ray cone
+                    let child = nodes[node_index];
+                    // Sparse cells are descended anyway, since most rays pass through them.
+                    if child.coverage() >= LOD_MIN_COVERAGE {
+                        let dx = pos.x - origin.x;
+                        let dy = pos.y - origin.y;
+                        let dz = pos.z - origin.z;
+                        let t = initial_ray_d + (dx * dx + dy * dy + dz * dz).sqrt();
+                        let level = cell_level(tree, self.scale_exp);
+                        for lane in lanes(active) {
+                            let cone_angle = states[lane].ray.cone_angle;
+                            let (diff, cell_size) =
+                                cone_excess(t.to_array()[lane], cone_angle, self.scale_exp);
+                            if diff.is_sign_positive() {
+                                let hit = self.hit(lane);
+                                let pos = pos.get(lane);
+                                hits[lane] = lod_hit(hit, pos, node, child, diff, cell_size, level);
+                                active &= !(1 << lane);
+                            }
+                        }
+                        if active.count_ones() < 2 {
+                            continue 'step;
+                        }
+                    }
+                }
+
+                self.gs_stack[self.scale_exp >> 1] = self.node_index;
+                self.attribute_stack[self.scale_exp >> 1] = self.attribute_index;
+                self.node_index = node_index;
+                self.node = nodes[node_index];
+                self.attribute_index += self.node.attribute_offset;
+                self.reads += 1;
+                self.scale_exp -= 2;
+                child_index = child_indices(&pos, self.scale_exp, mirror_mask);
+            }
+
+            let node = self.node;
+            let children = child_index.to_array();
+            if node.is_leaf() {
+                for lane in lanes(active) {
+                    if bit(node.mask, children[lane] as usize) {
+                        hits[lane] = leaf_hit(
+                            tree,
+                            self.hit(lane),
+                            pos.get(lane),
+                            states[lane].ray.direction,
+                            self.scale_exp,
+                            node,
+                            self.attribute_index,
+                            side_dist.get(lane),
+                        );
+                        active &= !(1 << lane);
+                    }
+                }
+                if active.count_ones() < 2 {
+                    continue 'step;
+                }
+            }
+
+            // Compute next pos by intersecting with max cell sides
+            let empty = u32x4::new(children.map(|child| {
+                let empty = ((node.mask >> (child & 42)) & 0x00330033) == 0;
+                if empty { u32::MAX } else { 0 }
+            }));
+            let scale_exp = self.scale_exp as u32;
+            let floor_mask = empty.blend(
+                u32x4::splat(u32::MAX << (scale_exp + 1)),
+                u32x4::splat(u32::MAX << scale_exp),
+            );
+            let cell_min_x = cast::<_, u32x4>(pos.x) & floor_mask;
+            let cell_min_y = cast::<_, u32x4>(pos.y) & floor_mask;
+            let cell_min_z = cast::<_, u32x4>(pos.z) & floor_mask;
+            let new_side_dist = Vec3x4 {
+                x: (cast::<_, f32x4>(cell_min_x) - origin.x) * inv_dir.x,
+                y: (cast::<_, f32x4>(cell_min_y) - origin.y) * inv_dir.y,
+                z: (cast::<_, f32x4>(cell_min_z) - origin.z) * inv_dir.z,
+            };
+            let tmax = min(new_side_dist.x, min(new_side_dist.y, new_side_dist.z));
+            let max_dist_reached = active & tmax.cmp_gt(max_dist).move_mask() as u32;
+            for lane in lanes(max_dist_reached) {
+                hits[lane] = self.hit(lane);
+                hits[lane].miss(Outcome::MaxDistance);
+            }
+            active &= !max_dist_reached;
+            if active.count_ones() < 2 {
+                continue 'step;
+            }
+
+            let f: i32x4 = cast(empty.blend(
+                u32x4::splat((1 << (scale_exp + 1)) - 1),
+                u32x4::splat((1 << scale_exp) - 1),
+            ));
+            let neighbor_max = |cell_min: u32x4, side_dist: f32x4| -> f32x4 {
+                let at_side: i32x4 = cast(side_dist.cmp_eq(tmax));
+                let offset = at_side.blend(i32x4::splat(-1), f);
+                cast(cast::<_, i32x4>(cell_min) + offset)
+            };
+            pos = Vec3x4 {
+                x: min(
+                    origin.x - abs_dir.x * tmax,
+                    neighbor_max(cell_min_x, new_side_dist.x),
+                ),
+                y: min(
+                    origin.y - abs_dir.y * tmax,
+                    neighbor_max(cell_min_y, new_side_dist.y),
+                ),
+                z: min(
+                    origin.z - abs_dir.z * tmax,
+                    neighbor_max(cell_min_z, new_side_dist.z),
+                ),
+            };
+            side_dist = new_side_dist;
+
+            // Find common ancestor based on left-most carry bit
+            let combined = ((cast::<_, u32x4>(pos.x) ^ cell_min_x)
+                | (cast::<_, u32x4>(pos.y) ^ cell_min_y)
+                | (cast::<_, u32x4>(pos.z) ^ cell_min_z))
+                & u32x4::splat(0xFFAAAAAA);
+            let scale_exp = combined.to_array().map(|combined| {
+                let diff_exp = if combined == 0 {
+                    -1
+                } else {
+                    31 - combined.leading_zeros() as i32
+                };
+                diff_exp.max(self.scale_exp as i32) as usize
+            });
+            self.iteration += 1;
+
+            for lane in lanes(active) {
+                if scale_exp[lane] > 21 {
+                    hits[lane] = self.hit(lane);
+                    active &= !(1 << lane);
+                }
+            }
+            if active == 0 {
+                return;
+            }
+            let lead = active.trailing_zeros() as usize;
+            for lane in lanes(active) {
+                if scale_exp[lane] != scale_exp[lead] {
+                    let mut traversal = self.split(lane, &pos, &side_dist);
+                    traversal.ascend(tree, scale_exp[lane]);
+                    hits[lane] = finish(tree, traversal);
+                    active &= !(1 << lane);
+                }
+            }
+            if scale_exp[lead] != self.scale_exp {
+                self.scale_exp = scale_exp[lead];
+                self.node_index = self.gs_stack[self.scale_exp >> 1];
+                self.attribute_index = self.attribute_stack[self.scale_exp >> 1];
+                self.node = nodes[self.node_index];
+                self.reads += 1;
+            }
+        }
+
+        for lane in lanes(active) {
+            hits[lane] = self.hit(lane);
+            hits[lane].miss(Outcome::MaxIterations);
+        }
+    }
+
+    fn hit(&self, lane: usize) -> PackedHitInfo {
+        let mut hit = self.lanes[lane].hit;
+        hit.reads += self.reads;
+        hit
+    }
+
+    fn split(&self, lane: usize, pos: &Vec3x4, side_dist: &Vec3x4) -> Traversal {
+        let Lane {
+            ray,
+            inv_dir,
+            mirror_mask,
+            initial_ray_d,
+            max_dist,
+            ..
+        } = self.lanes[lane];
+        Traversal {
+            ray,
+            hit: self.hit(lane),
+            pos: pos.get(lane),
+            inv_dir,
+            side_dist: side_dist.get(lane),
+            mirror_mask,
+            initial_ray_d,
+            max_dist,
+            scale_exp: self.scale_exp,
+            node_index: self.node_index,
+            node: self.node,
+            attribute_index: self.attribute_index,
+            gs_stack: self.gs_stack,
+            attribute_stack: self.attribute_stack,
+            iteration: self.iteration,
+        }
+    }
+}
+
+// Continues a ray on its own. Kept out of line, since `Traversal::run` is inlined.
+#[inline(never)]
+fn finish(tree: &VoxelTree, traversal: Traversal) -> PackedHitInfo {
+    traversal.run::<false>(tree)
+}
+
+// Picks `a` only if it is smaller, like `Vec3::min` and `Vec3::min_element`. Side
+// distances of axis aligned rays can be NaN, where `f32x4::fast_min` differs between
+// targets.
+fn min(a: f32x4, b: f32x4) -> f32x4 {
+    a.cmp_lt(b).blend(a, b)
+}
+
+// Lane-wise `node_cell_index`.
+fn child_indices(pos: &Vec3x4, scale_exp: usize, mirror_mask: u32x4) -> u32x4 {
+    let cell = |x: f32x4| (cast::<_, u32x4>(x) >> scale_exp as u32) & u32x4::splat(3);
+    (cell(pos.x) + (cell(pos.z) << 2) + (cell(pos.y) << 4)) ^ mirror_mask
+}
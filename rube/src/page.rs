@@ -0,0 +1,766 @@
+// Trees split into separately compressed pages, so that worlds larger than memory can
+// be streamed in around the camera.
+//
+// Paged files share the header of `format`, with `Header::paged` set:
+//
+// [  4   |    2    |   n    |       8       |     n     |  ...  ]
+// [ RUBE | version | header | directory len | directory | pages ]
+//
+// The directory is a postcard encoded `Directory` holding every level above the page
+// roots, the nodes at `page_depth` whose children are stored in a `Page`. Within the
+// directory their `child_index_is_leaf` is `Node::UNLOADED`, and loading a page
+// appends it to `nodes` and `leaves` and points the page roots at it. The directory
+// and every page are compressed with `Header::compression` on their own.
+
+use crate::format::{self, Compression, Header, LoadError, SourceMetadata};
+use crate::material::Material;
+use crate::ray::{cell_pos, popcnt};
+use crate::tree::{Leaves, MaterialId, Node, VoxelTree};
+use fxhash::FxHashMap;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Directory {
+    nodes: Vec<Node>,
+    materials: Vec<Material>,
+    exp: u32,
+    /// Page roots span `2^page_exp` voxels along each axis.
+    page_exp: u32,
+    pages: Vec<PageEntry>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct PageEntry {
+    /// Indices into `Directory::nodes` of the page roots sharing this page.
+    slots: Vec<u32>,
+    /// Byte range of the compressed page, relative to the end of the directory.
+    offset: u64,
+    len: u64,
+}
+
+/// The children of a page root and everything beneath them, indexed from `0`.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Page {
+    nodes: Vec<Node>,
+    leaves: Vec<MaterialId>,
+}
+
+/// Returns the depth of the page roots when a tree spanning `2^exp` voxels is split
+/// into pages spanning `2^page_exp` voxels, or `None` if `page_exp` is not an even
+/// exponent in `4..exp`.
+pub fn page_depth(exp: u32, page_exp: u32) -> Option<u32> {
+    (page_exp.is_multiple_of(2) && (4..exp).contains(&page_exp)).then(|| (exp - page_exp) / 2)
+}
+
+/// Encodes `tree` split into pages spanning `2^page_exp` voxels.
+///
+/// Pages are always decoded, so `Compression::Mapped` is written as
+/// `Compression::None`. Panics if `page_depth` rejects `page_exp`.
+pub fn encode(
+    tree: &VoxelTree,
+    compression: Compression,
+    source: SourceMetadata,
+    page_exp: u32,
+) -> Vec<u8> {
+    let page_depth = page_depth(tree.exp, page_exp).expect("`page_exp` is in `4..exp`");
+    let compression = match compression {
+        Compression::Mapped => Compression::None,
+        compression => compression,
+    };
+    let mut builder = DirectoryBuilder {
+        tree,
+        page_depth,
+        nodes: vec![Node::default()],
+        remapped: FxHashMap::default(),
+        pages: Vec::new(),
+        page_indices: FxHashMap::default(),
+    };
+    let root = tree.nodes[0];
+    if root.mask != 0 {
+        builder.nodes[0] = builder.node(root, 0);
+    }
+
+    let mut pages = Vec::with_capacity(builder.pages.len());
+    let mut page_bytes = Vec::new();
+    for (root, slots) in builder.pages {
+        let page = compression.compress(&postcard::to_allocvec(&extract(tree, root)).unwrap());
+        pages.push(PageEntry {
+            slots,
+            offset: page_bytes.len() as u64,
+            len: page.len() as u64,
+        });
+        page_bytes.extend_from_slice(&page);
+    }
+    let directory = Directory {
+        nodes: builder.nodes,
+        materials: tree.materials.clone(),
+        exp: tree.exp,
+        page_exp,
+        pages,
+    };
+    let directory = compression.compress(&postcard::to_allocvec(&directory).unwrap());
+
+    let mut header = Header::new(tree, compression, source);
+    header.paged = true;
+    let mut bytes = Vec::with_capacity(directory.len() + page_bytes.len() + 64);
+    header.write(&mut bytes);
+    bytes.extend_from_slice(&(directory.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&directory);
+    bytes.extend_from_slice(&page_bytes);
+    bytes
+}
+
+/// Loads the directory and every page, producing the same tree as an unpaged file.
+pub(crate) fn decode(header: &Header, payload: &[u8]) -> Result<VoxelTree, LoadError> {
+    let (mut directory, pages) = read_directory(header, payload)?;
+    let page_depth = page_depth(directory.exp, directory.page_exp).unwrap();
+    let entries = std::mem::take(&mut directory.pages);
+    let mut tree = directory_tree(directory);
+    for entry in &entries {
+        let page = read_page(header.compression, pages, entry)?;
+        check_page(&tree, &entry.slots, page_depth, &page)?;
+        insert(&mut tree, &entry.slots, &page);
+    }
+    Ok(tree)
+}
+
+// Returns the directory and the bytes of the pages following it.
+fn read_directory<'a>(
+    header: &Header,
+    payload: &'a [u8],
+) -> Result<(Directory, &'a [u8]), LoadError> {
+    let (len, rest) = payload
+        .split_first_chunk::<8>()
+        .ok_or(LoadError::TruncatedPayload)?;
+    let len = usize::try_from(u64::from_le_bytes(*len))
+        .ok()
+        .filter(|&len| len <= rest.len())
+        .ok_or(LoadError::TruncatedPayload)?;
+    let (directory, pages) = rest.split_at(len);
+    let directory: Directory = postcard::from_bytes(&header.compression.decompress(directory)?)?;
+    match page_depth(directory.exp, directory.page_exp) {
+        Some(page_depth) if check_directory(&directory, page_depth) => Ok((directory, pages)),
+        _ => Err(LoadError::InvalidDirectory),
+    }
+}
+
+// Checks that the child ranges above the page roots are in bounds, and that the page
+// roots are exactly the unloaded nodes at `page_depth`, each in a single page.
+fn check_directory(directory: &Directory, page_depth: u32) -> bool {
+    #[derive(Clone, Copy, PartialEq)]
+    enum Slot {
+        None,
+        Unreached,
+        Reached,
+    }
+    let nodes = &directory.nodes;
+    let mut slots = vec![Slot::None; nodes.len()];
+    for &slot in directory.pages.iter().flat_map(|entry| &entry.slots) {
+        match slots.get_mut(slot as usize) {
+            Some(slot @ Slot::None) => *slot = Slot::Unreached,
+            _ => return false,
+        }
+    }
+
+    // Depth of every interior node reached so far, by index.
+    let mut depths = FxHashMap::default();
+    let mut stack = vec![(0, 0)];
+    while let Some((index, depth)) = stack.pop() {
+        let Some(node) = nodes.get(index) else {
+            return false;
+        };
+        if node.mask == 0 {
+            continue;
+        }
+        if depth == page_depth {
+            if node.is_loaded() || slots[index] == Slot::None {
+                return false;
+            }
+            slots[index] = Slot::Reached;
+            continue;
+        }
+        if !node.is_loaded() || node.is_leaf() {
+            return false;
+        }
+        match depths.insert(index, depth) {
+            Some(reached) if reached != depth => return false,
+            Some(_) => continue,
+            None => {}
+        }
+        let start = node.child_index();
+        let end = start + node.mask.count_ones() as usize;
+        if end > nodes.len() {
+            return false;
+        }
+        stack.extend((start..end).map(|child| (child, depth + 1)));
+    }
+    !slots.contains(&Slot::Unreached)
+}
+
+fn read_page(compression: Compression, pages: &[u8], entry: &PageEntry) -> Result<Page, LoadError> {
+    let range = usize::try_from(entry.offset)
+        .ok()
+        .zip(usize::try_from(entry.len).ok())
+        .and_then(|(offset, len)| Some(offset..offset.checked_add(len)?))
+        .filter(|range| range.end <= pages.len())
+        .ok_or(LoadError::TruncatedPayload)?;
+    let page = compression.decompress(&pages[range])?;
+    Ok(postcard::from_bytes(&page)?)
+}
+
+// A tree with every page unloaded.
+fn directory_tree(directory: Directory) -> VoxelTree {
+    VoxelTree::new(
+        directory.nodes.into(),
+        Leaves::for_materials(directory.materials.len()),
+        directory.materials,
+        directory.exp,
+    )
+}
+
+// Checks that `page` holds a well formed subtree beneath each of the page roots at
+// `slots`, and that appending it to `tree` keeps its indices within `u32`, so
+// `insert` can neither overflow nor point out of bounds.
+fn check_page(
+    tree: &VoxelTree,
+    slots: &[u32],
+    page_depth: u32,
+    page: &Page,
+) -> Result<(), LoadError> {
+    let fits = |len: usize, page_len: usize| {
+        len.checked_add(page_len)
+            .is_some_and(|len| len <= (u32::MAX >> 1) as usize)
+    };
+    if !fits(tree.nodes.len(), page.nodes.len()) || !fits(tree.leaves.len(), page.leaves.len()) {
+        return Err(LoadError::InvalidPage);
+    }
+    let mut checker = PageChecker {
+        page,
+        materials: tree.materials.len(),
+        leaf_depth: tree.depth() - 1,
+        visited: FxHashMap::default(),
+    };
+    for &slot in slots {
+        // Within the page, the children of every root start at `0`.
+        let root = Node {
+            child_index_is_leaf: 0,
+            ..tree.nodes[slot as usize]
+        };
+        checker.node(root, page_depth)?;
+    }
+    Ok(())
+}
+
+struct PageChecker<'a> {
+    page: &'a Page,
+    materials: usize,
+    leaf_depth: u32,
+    // Depth and voxel count of every checked node, keyed by mask and
+    // `child_index_is_leaf` like `validate::Validator`.
+    visited: FxHashMap<(u64, u32), (u32, u64)>,
+}
+
+impl PageChecker<'_> {
+    // Returns the number of voxels beneath `node` at `depth`. Only leaf nodes may sit
+    // on the last level, so a cycle ends in an error rather than recursing forever.
+    fn node(&mut self, node: Node, depth: u32) -> Result<u64, LoadError> {
+        if node.mask == 0 || !node.is_loaded() || node.is_leaf() != (depth == self.leaf_depth) {
+            return Err(LoadError::InvalidPage);
+        }
+        let key = (node.mask, node.child_index_is_leaf);
+        if let Some(&(visited_depth, voxels)) = self.visited.get(&key) {
+            return (visited_depth == depth)
+                .then_some(voxels)
+                .ok_or(LoadError::InvalidPage);
+        }
+
+        let start = node.child_index();
+        let end = start + node.mask.count_ones() as usize;
+        let voxels = if node.is_leaf() {
+            let leaves = self
+                .page
+                .leaves
+                .get(start..end)
+                .ok_or(LoadError::InvalidPage)?;
+            if leaves
+                .iter()
+                .any(|&material| material == 0 || material as usize >= self.materials)
+            {
+                return Err(LoadError::InvalidPage);
+            }
+            leaves.len() as u64
+        } else {
+            let children = self
+                .page
+                .nodes
+                .get(start..end)
+                .ok_or(LoadError::InvalidPage)?;
+            let mut voxels = 0;
+            for &child in children {
+                if child.attribute_offset as u64 != voxels {
+                    return Err(LoadError::InvalidPage);
+                }
+                voxels += self.node(child, depth + 1)?;
+            }
+            voxels
+        };
+        self.visited.insert(key, (depth, voxels));
+        Ok(voxels)
+    }
+}
+
+// Appends `page` to `tree` and points the page roots at `slots` to it, returning the
+// bytes appended. Pages read from a file must pass `check_page` first.
+fn insert(tree: &mut VoxelTree, slots: &[u32], page: &Page) -> usize {
+    let node_base = (tree.nodes.len() as u32) << 1;
+    let leaf_base = (tree.leaves.len() as u32) << 1;
+    tree.nodes
+        .to_mut()
+        .extend(page.nodes.iter().map(|node| Node {
+            child_index_is_leaf: node.child_index_is_leaf
+                + if node.is_leaf() { leaf_base } else { node_base },
+            ..*node
+        }));
+    tree.leaves.extend_from_slice(&page.leaves);
+    for &slot in slots {
+        tree.nodes[slot as usize].child_index_is_leaf = node_base;
+    }
+    size_of_val(page.nodes.as_slice()) + page.leaves.len() * tree.leaves.id_size()
+}
+
+// Copies the subtree beneath the page root `root` into its own arrays.
+fn extract(tree: &VoxelTree, root: Node) -> Page {
+    let mut extractor = Extractor {
+        tree,
+        page: Page {
+            nodes: Vec::new(),
+            leaves: Vec::new(),
+        },
+        remapped: FxHashMap::default(),
+    };
+    extractor.children(root);
+    extractor.page
+}
+
+struct Extractor<'a> {
+    tree: &'a VoxelTree,
+    page: Page,
+    // Maps `child_index_is_leaf` of the tree to the page, so shared subtrees stay
+    // shared.
+    remapped: FxHashMap<u32, u32>,
+}
+
+impl Extractor<'_> {
+    // Appends the children of `node` and returns their `child_index_is_leaf`.
+    fn children(&mut self, node: Node) -> u32 {
+        if let Some(&child_index_is_leaf) = self.remapped.get(&node.child_index_is_leaf) {
+            return child_index_is_leaf;
+        }
+        let start = node.child_index();
+        let end = start + node.mask.count_ones() as usize;
+        let child_index_is_leaf = if node.is_leaf() {
+            let leaf_index = self.page.leaves.len() as u32;
+            self.page.leaves.extend(self.tree.leaves.to_vec(start..end));
+            (leaf_index << 1) | 1
+        } else {
+            let child_index = self.page.nodes.len();
+            self.page
+                .nodes
+                .extend_from_slice(&self.tree.nodes[start..end]);
+            for i in child_index..child_index + (end - start) {
+                let child = self.page.nodes[i];
+                self.page.nodes[i].child_index_is_leaf = self.children(child);
+            }
+            (child_index as u32) << 1
+        };
+        self.remapped
+            .insert(node.child_index_is_leaf, child_index_is_leaf);
+        child_index_is_leaf
+    }
+}
+
+struct DirectoryBuilder<'a> {
+    tree: &'a VoxelTree,
+    page_depth: u32,
+    nodes: Vec<Node>,
+    // Maps `child_index_is_leaf` of the tree to the directory.
+    remapped: FxHashMap<u32, u32>,
+    // The original page root and the slots pointing at it.
+    pages: Vec<(Node, Vec<u32>)>,
+    // Maps `child_index_is_leaf` of a page root to its index in `pages`.
+    page_indices: FxHashMap<u32, usize>,
+}
+
+impl DirectoryBuilder<'_> {
+    // Copies `node` at `depth` above the page roots into the directory.
+    fn node(&mut self, node: Node, depth: u32) -> Node {
+        if let Some(&child_index_is_leaf) = self.remapped.get(&node.child_index_is_leaf) {
+            return Node {
+                child_index_is_leaf,
+                ..node
+            };
+        }
+        let start = node.child_index();
+        let end = start + node.mask.count_ones() as usize;
+        let child_index = self.nodes.len();
+        self.nodes.extend_from_slice(&self.tree.nodes[start..end]);
+        for slot in child_index..child_index + (end - start) {
+            let child = self.nodes[slot];
+            self.nodes[slot] = if depth + 1 == self.page_depth {
+                let page = *self
+                    .page_indices
+                    .entry(child.child_index_is_leaf)
+                    .or_insert_with(|| {
+                        self.pages.push((child, Vec::new()));
+                        self.pages.len() - 1
+                    });
+                self.pages[page].1.push(slot as u32);
+                Node {
+                    child_index_is_leaf: Node::UNLOADED,
+                    ..child
+                }
+            } else {
+                self.node(child, depth + 1)
+            };
+        }
+        let child_index_is_leaf = (child_index as u32) << 1;
+        self.remapped
+            .insert(node.child_index_is_leaf, child_index_is_leaf);
+        Node {
+            child_index_is_leaf,
+            ..node
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use pager::Pager;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod pager {
+    use super::*;
+    use fxhash::FxHashSet;
+    use glam::Vec3;
+    use std::sync::{Arc, Condvar, Mutex};
+
+    // Unreachable bytes tolerated before `Pager::update` compacts the tree, as long as
+    // they don't exceed the resident pages.
+    const COMPACT_BYTES: usize = 64 * 1024 * 1024;
+
+    /// Streams the pages of a paged tree in and out around the camera.
+    ///
+    /// Pages within `load_distance` of the camera are decoded on a background thread
+    /// and inserted by `Pager::update`, and pages beyond `unload_distance` are dropped
+    /// again. Until its page is loaded, rays stop at a page root and return its
+    /// `mip_map`.
+    ///
+    /// The pager keeps indices into `VoxelTree::nodes`, so the tree must not be edited
+    /// or compacted while it is paged. `Pager::update` and `Pager::load_near` return
+    /// `LoadError::StaleTree` without touching the tree if it was, or if they are
+    /// passed a different tree.
+    pub struct Pager {
+        /// In tree space, where the whole tree spans `1.0`.
+        pub load_distance: f32,
+        /// In tree space, should exceed `load_distance` so pages at the boundary aren't
+        /// reloaded every frame.
+        pub unload_distance: f32,
+        source: Arc<Source>,
+        // `VoxelTree::generation` of the tree returned by `Pager::open`.
+        generation: u64,
+        page_depth: u32,
+        // The directory occupies the start of `nodes` and is never moved.
+        directory_len: usize,
+        slots: Vec<Vec<u32>>,
+        slot_pages: FxHashMap<u32, usize>,
+        // Bytes appended for every resident page.
+        resident: FxHashMap<usize, usize>,
+        failed: FxHashSet<usize>,
+        unreachable_bytes: usize,
+        queue: Arc<Queue>,
+        loader: Option<std::thread::JoinHandle<()>>,
+    }
+
+    struct Source {
+        map: memmap2::Mmap,
+        compression: Compression,
+        // Offset of the first page in `map`.
+        pages_start: usize,
+        entries: Vec<PageEntry>,
+    }
+
+    impl Source {
+        fn read_page(&self, page: usize) -> Result<Page, LoadError> {
+            read_page(
+                self.compression,
+                &self.map[self.pages_start..],
+                &self.entries[page],
+            )
+        }
+    }
+
+    struct Queue {
+        state: Mutex<QueueState>,
+        wake: Condvar,
+    }
+
+    struct QueueState {
+        // Sorted by descending distance, so the loader pops the nearest page.
+        wanted: Vec<usize>,
+        // Decoded pages waiting for `Pager::update`.
+        loaded: Vec<(usize, Result<Page, LoadError>)>,
+        closed: bool,
+    }
+
+    impl Pager {
+        /// Memory maps the paged tree at `path` and returns it with only the directory
+        /// loaded, or `None` if the file is not paged.
+        ///
+        /// # Safety
+        ///
+        /// Like `VoxelTree::open`, the file must not be truncated or modified while the
+        /// pager is alive, since pages are decoded from the mapping as they load.
+        pub unsafe fn open(
+            path: impl AsRef<std::path::Path>,
+        ) -> Result<Option<(VoxelTree, Self)>, LoadError> {
+            let file = std::fs::File::open(path)?;
+            // SAFETY: The caller keeps the file unchanged while the pager is alive.
+            let map = unsafe { memmap2::Mmap::map(&file)? };
+            let (header, payload) = match Header::read(&map)? {
+                Some((header, payload)) if header.paged => (header, payload),
+                _ => return Ok(None),
+            };
+            let (mut directory, pages) = read_directory(&header, payload)?;
+            let pages_start = map.len() - pages.len();
+            let page_depth = page_depth(directory.exp, directory.page_exp).unwrap();
+            let page_size = (1u32 << directory.page_exp) as f32 / (1u32 << directory.exp) as f32;
+
+            let mut entries = std::mem::take(&mut directory.pages);
+            let slots = entries
+                .iter_mut()
+                .map(|entry| std::mem::take(&mut entry.slots))
+                .collect::<Vec<_>>();
+            let slot_pages = slots
+                .iter()
+                .enumerate()
+                .flat_map(|(page, slots)| slots.iter().map(move |&slot| (slot, page)))
+                .collect();
+            let tree = format::check(directory_tree(directory))?;
+
+            let source = Arc::new(Source {
+                map,
+                compression: header.compression,
+                pages_start,
+                entries,
+            });
+            let queue = Arc::new(Queue {
+                state: Mutex::new(QueueState {
+                    wanted: Vec::new(),
+                    loaded: Vec::new(),
+                    closed: false,
+                }),
+                wake: Condvar::new(),
+            });
+            let loader = {
+                let source = source.clone();
+                let queue = queue.clone();
+                std::thread::spawn(move || {
+                    loop {
+                        let page = {
+                            let mut state = queue.state.lock().unwrap();
+                            loop {
+                                if state.closed {
+                                    return;
+                                }
+                                if let Some(page) = state.wanted.pop() {
+                                    break page;
+                                }
+                                state = queue.wake.wait(state).unwrap();
+                            }
+                        };
+                        let result = source.read_page(page);
+                        queue.state.lock().unwrap().loaded.push((page, result));
+                    }
+                })
+            };
+
+            let pager = Self {
+                load_distance: page_size * 4.0,
+                unload_distance: page_size * 6.0,
+                source,
+                generation: tree.generation,
+                page_depth,
+                directory_len: tree.nodes.len(),
+                slots,
+                slot_pages,
+                resident: FxHashMap::default(),
+                failed: FxHashSet::default(),
+                unreachable_bytes: 0,
+                queue,
+                loader: Some(loader),
+            };
+            Ok(Some((tree, pager)))
+        }
+
+        /// Number of pages in the file.
+        pub fn pages(&self) -> usize {
+            self.slots.len()
+        }
+
+        /// Number of pages currently inserted into the tree.
+        pub fn resident_pages(&self) -> usize {
+            self.resident.len()
+        }
+
+        /// Inserts the pages decoded since the last call, unloads distant pages and
+        /// queues the missing pages around `camera`, nearest first.
+        ///
+        /// Returns the first error of a page that failed to decode, which is not
+        /// requested again.
+        pub fn update(&mut self, tree: &mut VoxelTree, camera: Vec3) -> Result<(), LoadError> {
+            self.check_generation(tree)?;
+            let mut result = Ok(());
+            let loaded = std::mem::take(&mut self.queue.state.lock().unwrap().loaded);
+            for (page, loaded) in loaded {
+                if let Err(err) = loaded.and_then(|data| self.insert(tree, page, &data)) {
+                    self.failed.insert(page);
+                    result = result.and(Err(err));
+                }
+            }
+
+            let near = self.near_pages(tree, camera)?;
+            let unloaded = self
+                .resident
+                .keys()
+                .filter(|page| !near.contains_key(page))
+                .copied()
+                .collect::<Vec<_>>();
+            for page in unloaded {
+                for &slot in &self.slots[page] {
+                    tree.nodes[slot as usize].child_index_is_leaf = Node::UNLOADED;
+                }
+                self.unreachable_bytes += self.resident.remove(&page).unwrap();
+            }
+
+            let mut wanted = near
+                .into_iter()
+                .filter(|(page, distance)| {
+                    *distance <= self.load_distance
+                        && !self.resident.contains_key(page)
+                        && !self.failed.contains(page)
+                })
+                .collect::<Vec<_>>();
+            wanted.sort_by(|a, b| b.1.total_cmp(&a.1));
+            self.queue.state.lock().unwrap().wanted =
+                wanted.into_iter().map(|(page, _)| page).collect();
+            self.queue.wake.notify_one();
+
+            if self.unreachable_bytes > COMPACT_BYTES.max(self.resident.values().sum()) {
+                self.compact(tree);
+            }
+            result
+        }
+
+        /// Loads every page within `load_distance` of `camera` on the calling thread,
+        /// e.g. before rendering a single frame.
+        pub fn load_near(&mut self, tree: &mut VoxelTree, camera: Vec3) -> Result<(), LoadError> {
+            self.check_generation(tree)?;
+            for (page, distance) in self.near_pages(tree, camera)? {
+                if distance <= self.load_distance && !self.resident.contains_key(&page) {
+                    let data = self.source.read_page(page)?;
+                    self.insert(tree, page, &data)?;
+                }
+            }
+            Ok(())
+        }
+
+        fn check_generation(&self, tree: &VoxelTree) -> Result<(), LoadError> {
+            if tree.generation != self.generation {
+                return Err(LoadError::StaleTree);
+            }
+            Ok(())
+        }
+
+        fn insert(
+            &mut self,
+            tree: &mut VoxelTree,
+            page: usize,
+            data: &Page,
+        ) -> Result<(), LoadError> {
+            // The loader may decode a page twice if it was requested again while in
+            // flight.
+            if !self.resident.contains_key(&page) {
+                check_page(tree, &self.slots[page], self.page_depth, data)?;
+                let bytes = insert(tree, &self.slots[page], data);
+                self.resident.insert(page, bytes);
+            }
+            Ok(())
+        }
+
+        // Returns the pages within `unload_distance` of `camera`, along with the
+        // distance to their nearest root.
+        fn near_pages(
+            &self,
+            tree: &VoxelTree,
+            camera: Vec3,
+        ) -> Result<FxHashMap<usize, f32>, LoadError> {
+            let mut near = FxHashMap::default();
+            // (node, min corner in tree space, depth)
+            let mut stack = vec![(tree.nodes[0], Vec3::ONE, 0)];
+            while let Some((node, min, depth)) = stack.pop() {
+                let cell_size = 0.25f32.powi(depth as i32 + 1);
+                let mut remaining = node.mask;
+                while remaining != 0 {
+                    let child_index = remaining.trailing_zeros() as usize;
+                    remaining &= remaining - 1;
+                    let child_min = min + cell_pos(child_index).as_vec3() * cell_size;
+                    let nearest = camera.clamp(child_min, child_min + cell_size);
+                    let distance = camera.distance(nearest);
+                    if distance > self.unload_distance {
+                        continue;
+                    }
+                    let slot = node.child_index() + popcnt(node.mask, child_index);
+                    if depth + 1 == self.page_depth {
+                        let &page = self
+                            .slot_pages
+                            .get(&(slot as u32))
+                            .ok_or(LoadError::InvalidDirectory)?;
+                        near.entry(page)
+                            .and_modify(|nearest: &mut f32| *nearest = nearest.min(distance))
+                            .or_insert(distance);
+                    } else {
+                        stack.push((tree.nodes[slot], child_min, depth + 1));
+                    }
+                }
+            }
+            Ok(near)
+        }
+
+        // Drops the pages left unreachable by unloading, copying every resident page
+        // back in after the directory.
+        fn compact(&mut self, tree: &mut VoxelTree) {
+            let pages = self
+                .resident
+                .keys()
+                .map(|&page| {
+                    let root = tree.nodes[self.slots[page][0] as usize];
+                    (page, extract(tree, root))
+                })
+                .collect::<Vec<_>>();
+            tree.nodes.to_mut().truncate(self.directory_len);
+            tree.leaves = Leaves::for_materials(tree.materials.len());
+            for (page, data) in pages {
+                insert(tree, &self.slots[page], &data);
+            }
+            self.unreachable_bytes = 0;
+        }
+    }
+
+    impl Drop for Pager {
+        fn drop(&mut self) {
+            self.queue.state.lock().unwrap().closed = true;
+            self.queue.wake.notify_one();
+            if let Some(loader) = self.loader.take() {
+                let _ = loader.join();
+            }
+        }
+    }
+}
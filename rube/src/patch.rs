@@ -0,0 +1,491 @@
+// Differences between two trees, for distributing edits without shipping whole trees.
+//
+// A patch is a list of subtree replacements keyed by position and scale. `Patch::diff`
+// walks both trees together, skipping identical subtrees, and replaces the smallest
+// subtrees that differ: single leaf nodes where only voxels changed, and whole subtrees
+// where the source is empty. Replaced subtrees are stored as the masks of their nodes
+// in pre-order followed by their material ids, without mip maps or attribute offsets,
+// which are rebuilt when the patch is applied. A changed material table is stored
+// whole, and every mip map is rebuilt from it instead of patching each one.
+//
+// The file layout is `PATCH_MAGIC`, the format version as a little endian `u16`, the
+// postcard encoded `Compression` and the compressed postcard encoded `Patch`.
+
+use crate::format::{Compression, LoadError};
+use crate::material::Material;
+use crate::ray::{bit, cell_index, cell_pos, popcnt};
+use crate::tree::{MAX_EXP, MIN_EXP, MaterialId, Node, VoxelTree};
+use fxhash::{FxHashMap, FxHasher64};
+use glam::IVec3;
+use std::hash::Hasher;
+
+pub const PATCH_MAGIC: [u8; 4] = *b"RUBP";
+pub const PATCH_VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum PatchError {
+    /// The patch could not be read or decoded.
+    Load(LoadError),
+    /// The bytes don't start with `PATCH_MAGIC`.
+    MissingMagic,
+    /// The patch was written by a newer version of the format.
+    UnsupportedVersion(u16),
+    /// The `VoxelTree::content_hash` of the tree differs from the tree the patch was
+    /// made from.
+    SourceMismatch { expected: u64, found: u64 },
+    /// A change lies outside of the tree or its subtree is malformed.
+    InvalidChange(usize),
+}
+
+impl std::fmt::Display for PatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Load(err) => write!(f, "failed to load patch: {err}"),
+            Self::MissingMagic => write!(f, "not a patch file"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "patch format version {version} is newer than the supported version \
+                 {PATCH_VERSION}"
+            ),
+            Self::SourceMismatch { expected, found } => write!(
+                f,
+                "patch expects a tree with content hash {expected:016x} but the tree has \
+                 {found:016x}"
+            ),
+            Self::InvalidChange(change) => write!(f, "change {change} of the patch is invalid"),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Load(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<LoadError> for PatchError {
+    fn from(err: LoadError) -> Self {
+        Self::Load(err)
+    }
+}
+
+impl From<postcard::Error> for PatchError {
+    fn from(err: postcard::Error) -> Self {
+        Self::Load(LoadError::Decode(err))
+    }
+}
+
+/// Turns one tree into another, see `Patch::diff` and `VoxelTree::apply_patch`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Patch {
+    /// `VoxelTree::content_hash` of the source tree, checked before applying.
+    pub source_hash: u64,
+    /// Depth of the target tree. If it differs from the source, the first change
+    /// replaces the whole tree.
+    pub exp: u32,
+    /// Material table of the target tree, if it differs from the source.
+    pub materials: Option<Vec<Material>>,
+    /// Applied in order.
+    pub changes: Vec<Change>,
+}
+
+/// Replaces the subtree spanning `2^scale` voxels from `pos`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Change {
+    /// Must be a multiple of `2^scale`.
+    pub pos: [i32; 3],
+    pub scale: u32,
+    /// Masks of the replacement subtree in pre-order, a single `0` clears the region.
+    pub masks: Vec<u64>,
+    /// Material ids of the leaf nodes in the order of `masks`.
+    pub materials: Vec<MaterialId>,
+}
+
+impl Patch {
+    /// Returns the changes that turn `source` into `target`.
+    ///
+    /// Panics if either tree has unloaded pages.
+    pub fn diff(source: &VoxelTree, target: &VoxelTree) -> Self {
+        let mut diff = Diff {
+            source,
+            target,
+            same: FxHashMap::default(),
+            mip_maps: source.materials == target.materials,
+            changes: Vec::new(),
+        };
+        if source.exp == target.exp {
+            diff.node(source.nodes[0], target.nodes[0], target.exp, IVec3::ZERO);
+        } else {
+            diff.change(target.nodes[0], target.exp, IVec3::ZERO);
+        }
+        let patch = Self {
+            source_hash: source.content_hash(),
+            exp: target.exp,
+            materials: (source.materials != target.materials).then(|| target.materials.clone()),
+            changes: diff.changes,
+        };
+
+        // `VoxelTree::apply_patch` promises the compacted target back, given up to
+        // date mip maps.
+        #[cfg(debug_assertions)]
+        {
+            let compacted = |tree: &VoxelTree| {
+                let mut tree = VoxelTree::new(
+                    tree.nodes.clone(),
+                    tree.leaves.clone(),
+                    tree.materials.clone(),
+                    tree.exp,
+                );
+                tree.rebuild_mip_maps();
+                tree.compact();
+                tree
+            };
+            let mut patched = compacted(source);
+            let target = compacted(target);
+            debug_assert!(
+                patched.apply_patch(&patch).is_ok()
+                    && patched.exp == target.exp
+                    && patched.nodes == target.nodes
+                    && patched.leaves == target.leaves
+                    && patched.materials == target.materials,
+                "applying the patch doesn't reproduce the target"
+            );
+        }
+        patch
+    }
+
+    pub fn encode(&self, compression: Compression) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&PATCH_MAGIC);
+        bytes.extend_from_slice(&PATCH_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&postcard::to_allocvec(&compression).unwrap());
+        bytes.extend_from_slice(&compression.compress(&postcard::to_allocvec(self).unwrap()));
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, PatchError> {
+        let rest = bytes
+            .strip_prefix(&PATCH_MAGIC)
+            .ok_or(PatchError::MissingMagic)?;
+        let (version, rest) = rest
+            .split_first_chunk::<2>()
+            .ok_or(LoadError::TruncatedHeader)?;
+        let version = u16::from_le_bytes(*version);
+        if version > PATCH_VERSION {
+            return Err(PatchError::UnsupportedVersion(version));
+        }
+        let (compression, payload) = postcard::take_from_bytes::<Compression>(rest)?;
+        Ok(postcard::from_bytes(&compression.decompress(payload)?)?)
+    }
+}
+
+impl VoxelTree {
+    /// Applies the changes of `patch` and compacts the tree.
+    ///
+    /// Applying `Patch::diff(source, target)` to a compacted `source`, like any
+    /// generated or loaded tree, yields a tree identical to the compacted `target`.
+    /// Mip maps away from the changes are kept from `source`, so they only match if
+    /// both trees' mip maps are up to date, see `VoxelTree::rebuild_mip_maps`.
+    /// The tree is left unchanged if the patch doesn't fit. Unloaded pages are
+    /// treated as empty.
+    pub fn apply_patch(&mut self, patch: &Patch) -> Result<(), PatchError> {
+        let found = self.content_hash();
+        if found != patch.source_hash {
+            return Err(PatchError::SourceMismatch {
+                expected: patch.source_hash,
+                found,
+            });
+        }
+        if !patch.exp.is_multiple_of(2) || !(MIN_EXP..=MAX_EXP).contains(&patch.exp) {
+            return Err(LoadError::UnsupportedExp(patch.exp).into());
+        }
+        let materials = patch.materials.as_ref().unwrap_or(&self.materials).len();
+        for (index, change) in patch.changes.iter().enumerate() {
+            if !change.is_valid(patch.exp, materials) {
+                return Err(PatchError::InvalidChange(index));
+            }
+        }
+
+        if let Some(materials) = &patch.materials {
+            self.materials = materials.clone();
+            self.rebuild_mip_maps();
+        }
+        if patch.exp != self.exp {
+            self.exp = patch.exp;
+            self.nodes = vec![Node::default()].into();
+            self.leaves = Default::default();
+        }
+        for change in &patch.changes {
+            let subtree = self.build(
+                change.scale,
+                &mut change.masks.iter(),
+                &mut change.materials.iter(),
+            );
+            let root = self.nodes[0];
+            let pos = IVec3::from_array(change.pos);
+            self.nodes[0] = self.replace(root, self.exp, pos, change.scale, subtree);
+        }
+        self.compact();
+        Ok(())
+    }
+
+    /// Hashes the depth, material table and voxels of the tree, independent of how
+    /// `nodes` and `leaves` are laid out or shared. Mip maps and attribute offsets are
+    /// derived from these and not hashed. Unloaded pages hash as empty.
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = FxHasher64::default();
+        hasher.write_u32(self.exp);
+        hasher.write(&postcard::to_allocvec(&self.materials).unwrap());
+        let root = self.nodes[0];
+        hasher.write_u64(self.subtree_hash(root, &mut FxHashMap::default()));
+        hasher.finish()
+    }
+
+    // `hashed` is keyed by mask and `child_index_is_leaf`, as leaf groups are shared
+    // regardless of the mask.
+    fn subtree_hash(&self, node: Node, hashed: &mut FxHashMap<SubtreeKey, u64>) -> u64 {
+        if node.mask == 0 || !node.is_loaded() {
+            return 0;
+        }
+        if let Some(&hash) = hashed.get(&(node.mask, node.child_index_is_leaf)) {
+            return hash;
+        }
+        let mut hasher = FxHasher64::default();
+        hasher.write_u64(node.mask);
+        let start = node.child_index();
+        let end = start + node.mask.count_ones() as usize;
+        if node.is_leaf() {
+            for material in self.leaves.to_vec(start..end) {
+                hasher.write_u16(material);
+            }
+        } else {
+            for index in start..end {
+                hasher.write_u64(self.subtree_hash(self.nodes[index], hashed));
+            }
+        }
+        let hash = hasher.finish();
+        hashed.insert((node.mask, node.child_index_is_leaf), hash);
+        hash
+    }
+
+    // Appends the subtree of `scale` described by `masks` and `materials`, which
+    // `Change::is_valid` checked.
+    fn build(
+        &mut self,
+        scale: u32,
+        masks: &mut std::slice::Iter<u64>,
+        materials: &mut std::slice::Iter<MaterialId>,
+    ) -> Node {
+        let mask = *masks.next().unwrap();
+        if mask == 0 {
+            return Node::default();
+        }
+        if scale == 2 {
+            let mut cells = [0; 64];
+            for (i, cell) in cells.iter_mut().enumerate() {
+                if bit(mask, i) {
+                    *cell = *materials.next().unwrap();
+                }
+            }
+            return self.push_leaf_node(&cells);
+        }
+        let mut children = [Node::default(); 64];
+        for (i, child) in children.iter_mut().enumerate() {
+            if bit(mask, i) {
+                *child = self.build(scale - 2, masks, materials);
+            }
+        }
+        self.push_interior_node(&children)
+    }
+
+    // Replaces the subtree of `target_scale` containing `target` beneath `node`,
+    // rebuilding the path to it like `VoxelTree::edit`.
+    fn replace(
+        &mut self,
+        node: Node,
+        scale: u32,
+        target: IVec3,
+        target_scale: u32,
+        subtree: Node,
+    ) -> Node {
+        if scale == target_scale {
+            return subtree;
+        }
+        let scale = scale - 2;
+        let mut children = [Node::default(); 64];
+        if node.is_loaded() {
+            for (i, child) in children.iter_mut().enumerate() {
+                if bit(node.mask, i) {
+                    *child = self.nodes[node.child_index() + popcnt(node.mask, i)];
+                }
+            }
+        }
+        let i = cell_index(target, scale);
+        let replaced = self.replace(children[i], scale, target, target_scale, subtree);
+        if replaced == children[i] {
+            return node;
+        }
+        children[i] = replaced;
+        self.push_interior_node(&children)
+    }
+}
+
+impl Change {
+    // Checks the region against a tree of `2^exp` voxels and that the subtree is
+    // complete, with every stored node non-empty and every id in the material table.
+    fn is_valid(&self, exp: u32, materials: usize) -> bool {
+        let pos = IVec3::from_array(self.pos);
+        if !self.scale.is_multiple_of(2)
+            || !(2..=exp).contains(&self.scale)
+            || pos.cmplt(IVec3::ZERO).any()
+            || pos.cmpge(IVec3::splat(1 << exp)).any()
+            || pos & IVec3::splat((1 << self.scale) - 1) != IVec3::ZERO
+        {
+            return false;
+        }
+        let (mut masks, mut ids) = (self.masks.iter(), self.materials.iter());
+        match masks.next() {
+            Some(0) => {}
+            Some(&mask) => {
+                if !subtree_is_valid(mask, self.scale, &mut masks, &mut ids, materials) {
+                    return false;
+                }
+            }
+            None => return false,
+        }
+        masks.next().is_none() && ids.next().is_none()
+    }
+}
+
+fn subtree_is_valid(
+    mask: u64,
+    scale: u32,
+    masks: &mut std::slice::Iter<u64>,
+    ids: &mut std::slice::Iter<MaterialId>,
+    materials: usize,
+) -> bool {
+    if scale == 2 {
+        return (0..mask.count_ones()).all(|_| {
+            ids.next()
+                .is_some_and(|&id| id != 0 && (id as usize) < materials)
+        });
+    }
+    (0..mask.count_ones()).all(|_| match masks.next() {
+        Some(&child) if child != 0 => subtree_is_valid(child, scale - 2, masks, ids, materials),
+        _ => false,
+    })
+}
+
+// Mask and `child_index_is_leaf` of a node.
+type SubtreeKey = (u64, u32);
+
+struct Diff<'a> {
+    source: &'a VoxelTree,
+    target: &'a VoxelTree,
+    // Whether subtrees of the source and target are identical, keyed by their masks and
+    // `child_index_is_leaf`, as leaf groups are shared regardless of the mask.
+    same: FxHashMap<(SubtreeKey, SubtreeKey), bool>,
+    // Whether mip maps are compared, which is skipped when the material table changed
+    // since `VoxelTree::apply_patch` rebuilds them all.
+    mip_maps: bool,
+    changes: Vec<Change>,
+}
+
+impl Diff<'_> {
+    fn node(&mut self, source: Node, target: Node, scale: u32, pos: IVec3) {
+        if self.same(source, target) {
+            return;
+        }
+        if scale == 2 || source.mask == 0 || target.mask == 0 {
+            self.change(target, scale, pos);
+            return;
+        }
+        let scale = scale - 2;
+        for i in 0..64 {
+            if !bit(source.mask, i) && !bit(target.mask, i) {
+                continue;
+            }
+            let child = |tree: &VoxelTree, node: Node| {
+                if bit(node.mask, i) {
+                    tree.nodes[node.child_index() + popcnt(node.mask, i)]
+                } else {
+                    Node::default()
+                }
+            };
+            let (source_child, target_child) =
+                (child(self.source, source), child(self.target, target));
+            let child_pos = pos + (cell_pos(i) << scale);
+            self.node(source_child, target_child, scale, child_pos);
+        }
+    }
+
+    fn same(&mut self, source: Node, target: Node) -> bool {
+        // Without material changes, mip maps only differ where ids differ.
+        if source.mask != target.mask || (self.mip_maps && source.mip_map != target.mip_map) {
+            return false;
+        }
+        if source.mask == 0 {
+            return true;
+        }
+        assert!(
+            source.is_loaded() && target.is_loaded(),
+            "can't diff unloaded pages"
+        );
+        let key = (
+            (source.mask, source.child_index_is_leaf),
+            (target.mask, target.child_index_is_leaf),
+        );
+        if let Some(&same) = self.same.get(&key) {
+            return same;
+        }
+        let (source_start, target_start) = (source.child_index(), target.child_index());
+        let len = source.mask.count_ones() as usize;
+        let same = if source.is_leaf() {
+            self.source.leaves.to_vec(source_start..source_start + len)
+                == self.target.leaves.to_vec(target_start..target_start + len)
+        } else {
+            (0..len).all(|i| {
+                self.same(
+                    self.source.nodes[source_start + i],
+                    self.target.nodes[target_start + i],
+                )
+            })
+        };
+        self.same.insert(key, same);
+        same
+    }
+
+    // Replaces the region with the subtree of `target`.
+    fn change(&mut self, target: Node, scale: u32, pos: IVec3) {
+        let mut change = Change {
+            pos: pos.to_array(),
+            scale,
+            masks: Vec::new(),
+            materials: Vec::new(),
+        };
+        self.write(target, &mut change);
+        self.changes.push(change);
+    }
+
+    fn write(&self, node: Node, change: &mut Change) {
+        change.masks.push(node.mask);
+        if node.mask == 0 {
+            return;
+        }
+        assert!(node.is_loaded(), "can't diff unloaded pages");
+        let start = node.child_index();
+        let end = start + node.mask.count_ones() as usize;
+        if node.is_leaf() {
+            change
+                .materials
+                .extend(self.target.leaves.to_vec(start..end));
+        } else {
+            for &child in &self.target.nodes[start..end] {
+                self.write(child, change);
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+// Read queries on a `VoxelTree` in integer voxel coordinates.
+
+use crate::ray::{bit, cell_index, cell_pos, popcnt};
+use crate::tree::{MaterialId, Node, VoxelTree, subtree_voxels};
+use glam::IVec3;
+
+impl VoxelTree {
+    /// Returns the material of the voxel at `pos`, or `None` if it is empty, out of
+    /// bounds or in an unloaded page.
+    pub fn get(&self, pos: IVec3) -> Option<MaterialId> {
+        if pos.cmplt(IVec3::ZERO).any() || pos.cmpge(IVec3::splat(1 << self.exp)).any() {
+            return None;
+        }
+        let mut node = self.nodes[0];
+        let mut scale = self.exp;
+        loop {
+            scale -= 2;
+            let child_index = cell_index(pos, scale);
+            if !bit(node.mask, child_index) || !node.is_loaded() {
+                return None;
+            }
+            let index = node.child_index() + popcnt(node.mask, child_index);
+            if node.is_leaf() {
+                return Some(self.leaves.get(index));
+            }
+            node = self.nodes[index];
+        }
+    }
+
+    /// Counts the solid voxels in `min..max`, skipping unloaded pages.
+    pub fn count_in_aabb(&self, min: IVec3, max: IVec3) -> u64 {
+        let min = min.max(IVec3::ZERO);
+        let max = max.min(IVec3::splat(1 << self.exp));
+        if min.cmpge(max).any() {
+            return 0;
+        }
+        self.count_node(self.nodes[0], self.exp, IVec3::ZERO, min, max)
+    }
+
+    fn count_node(&self, node: Node, scale: u32, pos: IVec3, min: IVec3, max: IVec3) -> u64 {
+        // Fully contained subtrees are counted without descending.
+        if pos.cmpge(min).all() && (pos + IVec3::splat(1 << scale)).cmple(max).all() {
+            return subtree_voxels(&self.nodes, node) as u64;
+        }
+
+        if !node.is_loaded() {
+            return 0;
+        }
+        let scale = scale - 2;
+        let mut count = 0;
+        let mut remaining = node.mask;
+        while remaining != 0 {
+            let child_index = remaining.trailing_zeros() as usize;
+            remaining &= remaining - 1;
+            let child_pos = pos + (cell_pos(child_index) << scale);
+            let child_max = child_pos + IVec3::splat(1 << scale);
+            if child_pos.cmpge(max).any() || min.cmpge(child_max).any() {
+                continue;
+            }
+            if node.is_leaf() {
+                count += 1;
+            } else {
+                let child = self.nodes[node.child_index() + popcnt(node.mask, child_index)];
+                count += self.count_node(child, scale, child_pos, min, max);
+            }
+        }
+        count
+    }
+
+    /// Iterates over the position and material of every solid voxel.
+    ///
+    /// Voxels are yielded in attribute index order, see `Node::attribute_offset`.
+    /// Unloaded pages are skipped.
+    pub fn voxels(&self) -> impl Iterator<Item = (IVec3, MaterialId)> + '_ {
+        let root = self.nodes[0];
+        Voxels {
+            tree: self,
+            stack: vec![(root, IVec3::ZERO, self.exp - 2, root.mask)],
+        }
+    }
+}
+
+struct Voxels<'a> {
+    tree: &'a VoxelTree,
+    // (node, position, cell scale, remaining mask)
+    stack: Vec<(Node, IVec3, u32, u64)>,
+}
+
+impl Iterator for Voxels<'_> {
+    type Item = (IVec3, MaterialId);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (node, pos, scale, remaining) = self.stack.last_mut()?;
+            if *remaining == 0 || !node.is_loaded() {
+                self.stack.pop();
+                continue;
+            }
+            let child_index = remaining.trailing_zeros() as usize;
+            *remaining &= *remaining - 1;
+
+            let index = node.child_index() + popcnt(node.mask, child_index);
+            let child_pos = *pos + (cell_pos(child_index) << *scale);
+            if node.is_leaf() {
+                return Some((child_pos, self.tree.leaves.get(index)));
+            }
+            let child = self.tree.nodes[index];
+            let child_scale = *scale - 2;
+            self.stack.push((child, child_pos, child_scale, child.mask));
+        }
+    }
+}
@@ -1,15 +1,43 @@
 // Sparse-64 voxel tree ray marcher implementation adapted from:
 // https://dubiousconst282.github.io/2024/10/03/voxel-ray-tracing/
 
-use crate::tree::VoxelTree;
+use crate::packet::{LANES, cast_packet};
+use crate::tree::{MaterialId, Node, VoxelTree};
 use glam::{IVec3, UVec3, Vec3};
 
-#[derive(Default, Clone, Copy)]
+// Cells smaller than the ray cone terminate LOD rays only if they cover at least this
+// fraction of rays, see `Node::coverage`.
+pub(crate) const LOD_MIN_COVERAGE: f32 = 0.5;
+
+// Set on `PackedHitInfo::mip_map` of coarse hits, which tells them apart from voxel
+// hits even where the average color is black.
+const COARSE_ALPHA: u32 = 0xFF00_0000;
+
+// Traversal steps after which a ray gives up with `Outcome::MaxIterations`.
+pub(crate) const MAX_ITERATIONS: usize = 256;
+
+/// How a cast ray ended, see `PackedHitInfo::outcome`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The ray hit a voxel, or a coarse node if `PackedHitInfo::is_coarse`.
+    Hit,
+    /// The ray left the tree.
+    Escaped,
+    /// The ray reached the end of its range, see `Ray::range`.
+    MaxDistance,
+    /// Traversal gave up before the ray hit anything or left the tree.
+    MaxIterations,
+}
+
+#[derive(Default, Clone, Copy, PartialEq)]
 pub struct PackedHitInfo {
     leaf_index_and_normal_and_escaped: u32,
+    attribute_index: u32,
     pub position: Vec3,
     // TODO: This needs to be better integrated. There is no point in storing leaf index
     // if the color data is already here.
+    /// Average color beneath a coarse hit with an opaque alpha, so it is never `0`,
+    /// and `0` for every other hit.
     pub mip_map: u32,
     pub reads: u32,
 }
@@ -19,6 +47,11 @@ impl PackedHitInfo {
         (self.leaf_index_and_normal_and_escaped >> 4) as usize
     }
 
+    /// Unique index of the hit voxel, see `Node::attribute_offset`.
+    pub fn attribute_index(&self) -> usize {
+        self.attribute_index as usize
+    }
+
     pub fn normal_index(&self) -> usize {
         ((self.leaf_index_and_normal_and_escaped >> 1) & 7) as usize
     }
@@ -35,16 +68,62 @@ impl PackedHitInfo {
         }
     }
 
-    pub fn escaped(&self) -> bool {
+    pub fn outcome(&self) -> Outcome {
+        if !self.missed() {
+            return Outcome::Hit;
+        }
+        // Missed rays store the reason in place of the normal.
+        match self.normal_index() {
+            0 => Outcome::Escaped,
+            1 => Outcome::MaxDistance,
+            _ => Outcome::MaxIterations,
+        }
+    }
+
+    /// Whether the ray ended without hitting anything, see `PackedHitInfo::outcome`.
+    pub fn missed(&self) -> bool {
         (self.leaf_index_and_normal_and_escaped & 1) == 1
     }
+
+    pub fn escaped(&self) -> bool {
+        self.outcome() == Outcome::Escaped
+    }
+
+    /// Whether the ray stopped at a coarse node, either through `Ray::lod` or at an
+    /// unloaded page, rather than at a voxel.
+    pub fn is_coarse(&self) -> bool {
+        !self.missed() && self.mip_map != 0
+    }
+
+    /// Detail level of a coarse hit as the log2 of the hit cell's size in voxels, or
+    /// `None` if the ray hit a voxel or nothing.
+    pub fn lod_level(&self) -> Option<u32> {
+        if !self.is_coarse() {
+            return None;
+        }
+        // Coarse hits have no leaf, so its bits hold the level instead.
+        Some(self.leaf_index_and_normal_and_escaped >> 4)
+    }
+
+    pub(crate) fn miss(&mut self, outcome: Outcome) {
+        let reason = match outcome {
+            Outcome::Hit => unreachable!(),
+            Outcome::Escaped => 0,
+            Outcome::MaxDistance => 1,
+            Outcome::MaxIterations => 2,
+        };
+        self.leaf_index_and_normal_and_escaped = 1 | (reason << 1);
+    }
 }
 
 #[derive(Clone, Copy)]
 pub struct Ray {
-    origin: Vec3,
-    direction: Vec3,
-    lod: bool,
+    pub(crate) origin: Vec3,
+    pub(crate) direction: Vec3,
+    // Apex angle of the LOD cone in radians, `0` without LOD.
+    pub(crate) cone_angle: f32,
+    tmin: f32,
+    tmax: f32,
 }
 
 impl Ray {
@@ -52,208 +131,470 @@ impl Ray {
         Self {
             origin,
             direction,
-            lod: false,
+            cone_angle: 0.0,
+            tmin: 0.0,
+            tmax: f32::INFINITY,
         }
     }
 
-    pub fn lod(mut self) -> Self {
-        self.lod = true;
+    /// Limits the ray to `tmin..=tmax` in multiples of its direction.
+    ///
+    /// Voxels before `tmin` are skipped and rays reaching `tmax` end with
+    /// `Outcome::MaxDistance`.
+    pub fn range(mut self, tmin: f32, tmax: f32) -> Self {
+        self.tmin = tmin;
+        self.tmax = tmax;
         self
     }
 
-    pub fn cast(self, tree: &VoxelTree) -> PackedHitInfo {
-        cast_ray(tree, self)
+    /// Stops at coarse nodes once their cells are narrower than a cone around the ray
+    /// with an apex angle of `cone_angle` radians, e.g. the footprint of a pixel.
+    ///
+    /// Such hits are `PackedHitInfo::is_coarse`, see `PackedHitInfo::lod_level`.
+    pub fn lod(mut self, cone_angle: f32) -> Self {
+        self.cone_angle = cone_angle;
+        self
     }
-}
 
-fn cast_ray(tree: &VoxelTree, mut ray: Ray) -> PackedHitInfo {
-    let mut hit = PackedHitInfo {
-        leaf_index_and_normal_and_escaped: 1,
-        ..Default::default()
-    };
+    pub fn cast(self, tree: &VoxelTree) -> PackedHitInfo {
+        cast_ray::<false>(tree, self)
+    }
 
-    // Perform aabb intersection check before descending tree to prevent rays from
-    // starting outside of the 1..2 bounding volume. Rays can only traverse in this
-    // range so this check is required to support arbitrary camera positioning in
-    // the world.
-    let bbox_min = Vec3::splat(1.0);
-    let bbox_max = Vec3::splat(2.0);
-    let t0 = (bbox_min - ray.origin) / ray.direction;
-    let t1 = (bbox_max - ray.origin) / ray.direction;
-    let tmin = t0.min(t1);
-    let tmax = t0.max(t1);
-    let tnear = tmin.max_element();
-    let tfar = tmax.min_element();
-
-    if tnear > tfar || tfar < 0.0 {
-        return hit;
+    /// Casts neighboring rays together, returning the same hits as `Ray::cast`.
+    ///
+    /// The rays share node reads and do their math side by side while they visit the
+    /// same nodes, which pays off for coherent rays like the primary rays of a 2x2
+    /// block of pixels.
+    pub fn cast_packet(rays: [Self; LANES], tree: &VoxelTree) -> [PackedHitInfo; LANES] {
+        let hits = cast_packet(tree, rays);
+        debug_assert!(
+            hits == rays.map(|ray| ray.cast(tree)),
+            "packet hits differ from the hits of single rays"
+        );
+        hits
     }
 
-    if tnear > 0.0 {
-        ray.origin += ray.direction * tnear;
+    /// Whether the ray hits anything, the same as `!ray.cast(tree).missed()`.
+    ///
+    /// Stops at the first leaf or coarse node without reconstructing the hit.
+    pub fn occluded(self, tree: &VoxelTree) -> bool {
+        !cast_ray::<true>(tree, self).missed()
     }
+}
 
-    let mut scale_exp = 21;
-    let mut node_index = 0;
-    let mut node = tree.nodes[node_index];
-    hit.reads += 1;
+/// A voxel hit by `raycast`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RaycastHit {
+    /// Distance to the entry point of `voxel` in multiples of the ray direction, `0` if
+    /// the ray starts inside of it.
+    pub t: f32,
+    pub voxel: IVec3,
+    /// Outward normal of the face the ray entered `voxel` through.
+    pub normal: IVec3,
+    pub material: MaterialId,
+    /// Number of nodes read during traversal.
+    pub reads: u32,
+}
 
-    // Mirror coordinates to negative ray octant to simplify cell intersections
-    let mut mirror_mask = 0;
-    if ray.direction.x > 0.0 {
-        mirror_mask |= 3;
+/// Returns the first solid voxel along the ray from `origin` in direction `dir`, up to
+/// a distance of `max_t` times `dir`.
+///
+/// Positions are in voxel units, voxel `v` spans `v..v + 1` and the tree spans
+/// `0..2^exp` along each axis. Returns `None` if the ray leaves the tree or reaches an
+/// unloaded page first.
+pub fn raycast(tree: &VoxelTree, origin: Vec3, dir: Vec3, max_t: f32) -> Option<RaycastHit> {
+    let voxel_size = tree.voxel_size();
+    let hit = Ray::new(Vec3::ONE + origin * voxel_size, dir)
+        .range(0.0, max_t * voxel_size)
+        .cast(tree);
+    // Without LOD, only unloaded pages end in coarse hits.
+    if hit.missed() || hit.is_coarse() {
+        return None;
     }
-    if ray.direction.y > 0.0 {
-        mirror_mask |= 3 << 4;
+
+    let voxel = ((hit.position - Vec3::ONE) / voxel_size).floor().as_ivec3();
+    // The traversal position is nudged into the voxel and its normal isn't set for
+    // voxels on the tree bounds, so both are taken from the voxel bounds instead.
+    let t0 = (voxel.as_vec3() - origin) / dir;
+    let t1 = (voxel.as_vec3() + Vec3::ONE - origin) / dir;
+    let entry = t0.min(t1);
+    let t = entry.max_element().max(0.0);
+    if t > max_t {
+        return None;
     }
-    if ray.direction.z > 0.0 {
-        mirror_mask |= 3 << 2;
+    let mut normal = IVec3::ZERO;
+    let axis = entry.max_position();
+    normal[axis] = if dir[axis] > 0.0 { -1 } else { 1 };
+    Some(RaycastHit {
+        t,
+        voxel,
+        normal,
+        material: tree.leaves.get(hit.leaf_index()),
+        reads: hit.reads,
+    })
+}
+
+// With `ANY_HIT` only the outcome and `reads` of the returned hit are set.
+fn cast_ray<const ANY_HIT: bool>(tree: &VoxelTree, ray: Ray) -> PackedHitInfo {
+    match Traversal::new(tree, ray) {
+        Ok(traversal) => traversal.run::<ANY_HIT>(tree),
+        Err(hit) => hit,
     }
+}
 
-    ray.origin = mirrored_pos(ray.origin, ray.direction, true);
-    // Clamp to prevent traversal from completely breaking for rays starting outside tree
-    let mut pos = ray.origin.clamp(Vec3::splat(1.0), Vec3::splat(1.9999999));
-    let inv_dir = 1.0 / -ray.direction.abs();
-
-    let initial_ray_d = tnear.max(0.0);
-
-    let mut gs_stack = [0; 11];
-
-    let mut side_dist = Vec3::ZERO;
-    for _ in 0..256 {
-        let mut child_index = node_cell_index(pos, scale_exp) ^ mirror_mask;
-        // Descend
-        while bit(node.mask, child_index) && !node.is_leaf() {
-            gs_stack[scale_exp >> 1] = node_index;
-            node_index = node.child_index() + popcnt(node.mask, child_index);
-
-            if ray.lod {
-                // mipmap early exit check with a ray cone
-                let t = initial_ray_d + (pos - ray.origin).length();
-                // let factor = 0.008;
-                let factor = 0.10;
-                let cell_size = f32::from_bits((scale_exp as u32 + 127 - 23) << 23);
-                let diff = t * factor - cell_size;
-                if diff.is_sign_positive() {
-                    let child_node = tree.nodes[node_index];
-                    let linear_mip_map = VoxelTree::unpack_srgb_linear(node.mip_map);
-                    let linear_child_mip_map = VoxelTree::unpack_srgb_linear(child_node.mip_map);
-                    let linear_mip_map = linear_child_mip_map
-                        .lerp(linear_mip_map, (diff / cell_size).clamp(0.0, 1.0));
-
-                    hit.position = pos;
-                    hit.mip_map = VoxelTree::pack_linear_rgb(linear_mip_map);
-                    hit.leaf_index_and_normal_and_escaped = 0;
-                    return hit;
-                }
-            }
+// State of a ray at the start of a traversal step, so packets can hand rays that
+// diverge from them over to `Traversal::run`.
+#[derive(Clone, Copy)]
+pub(crate) struct Traversal {
+    // The origin is mirrored to the negative octant, see `mirrored_pos`.
+    pub(crate) ray: Ray,
+    pub(crate) hit: PackedHitInfo,
+    pub(crate) pos: Vec3,
+    pub(crate) inv_dir: Vec3,
+    pub(crate) side_dist: Vec3,
+    pub(crate) mirror_mask: usize,
+    pub(crate) initial_ray_d: f32,
+    // Remaining range from the origin.
+    pub(crate) max_dist: f32,
+    pub(crate) scale_exp: usize,
+    pub(crate) node_index: usize,
+    pub(crate) node: Node,
+    pub(crate) attribute_index: u32,
+    pub(crate) gs_stack: [usize; 11],
+    pub(crate) attribute_stack: [u32; 11],
+    pub(crate) iteration: usize,
+}
 
-            node = tree.nodes[node_index];
-            hit.reads += 1;
-            scale_exp -= 2;
-            child_index = node_cell_index(pos, scale_exp) ^ mirror_mask;
+impl Traversal {
+    // Returns the final hit instead if the ray misses the tree or its range.
+    #[inline(always)]
+    pub(crate) fn new(tree: &VoxelTree, mut ray: Ray) -> Result<Self, PackedHitInfo> {
+        let mut hit = PackedHitInfo::default();
+        hit.miss(Outcome::Escaped);
+
+        // Perform aabb intersection check before descending tree to prevent rays from
+        // starting outside of the 1..2 bounding volume. Rays can only traverse in this
+        // range so this check is required to support arbitrary camera positioning in
+        // the world.
+        let bbox_min = Vec3::splat(1.0);
+        let bbox_max = Vec3::splat(2.0);
+        let t0 = (bbox_min - ray.origin) / ray.direction;
+        let t1 = (bbox_max - ray.origin) / ray.direction;
+        let tmin = t0.min(t1);
+        let tmax = t0.max(t1);
+        let tnear = tmin.max_element();
+        let tfar = tmax.min_element();
+
+        if tnear > tfar || tfar < ray.tmin {
+            return Err(hit);
         }
 
-        if bit(node.mask, child_index) && node.is_leaf() {
-            break;
+        let tstart = tnear.max(ray.tmin);
+        if tstart > ray.tmax {
+            hit.miss(Outcome::MaxDistance);
+            return Err(hit);
         }
-
-        let mut adv_scale_ecp = scale_exp;
-        // wtf
-        if ((node.mask >> (child_index & 42)) & 0x00330033) == 0 {
-            adv_scale_ecp += 1;
+        if tstart > 0.0 {
+            ray.origin += ray.direction * tstart;
         }
 
-        // Compute next pos by intersecting with max cell sides
-        let cell_min = floor_scale(pos, adv_scale_ecp);
-
-        side_dist = (cell_min - ray.origin) * inv_dir;
-        let tmax = side_dist.min_element();
-
-        let f = IVec3::splat((1 << adv_scale_ecp) - 1);
-        let t = IVec3::splat(-1);
-        let mask = side_dist.cmpeq(Vec3::splat(tmax));
-        let offset = IVec3::select(mask, t, f);
-        let neighbor_max = IVec3::new(
-            cell_min.x.to_bits() as i32,
-            cell_min.y.to_bits() as i32,
-            cell_min.z.to_bits() as i32,
-        ) + offset;
-        pos = (ray.origin - ray.direction.abs() * tmax).min(Vec3::new(
-            f32::from_bits(neighbor_max.x as u32),
-            f32::from_bits(neighbor_max.y as u32),
-            f32::from_bits(neighbor_max.z as u32),
-        ));
-
-        // Find common ancestor based on left-most carry bit
-        // We only care about changes in the exponent and high bits of
-        // each cell position (10'10'10'...), so the odd bits are masked.
-        let diff_pos = UVec3::new(pos.x.to_bits(), pos.y.to_bits(), pos.z.to_bits())
-            ^ UVec3::new(
-                cell_min.x.to_bits(),
-                cell_min.y.to_bits(),
-                cell_min.z.to_bits(),
-            );
-        let combined = (diff_pos.x | diff_pos.y | diff_pos.z) & 0xFFAAAAAA;
-        let diff_exp: i32 = if combined == 0 {
-            -1
-        } else {
-            31 - combined.leading_zeros() as i32
-        };
+        let node = tree.nodes[0];
+        hit.reads += 1;
 
-        if diff_exp > scale_exp as i32 {
-            // NOTE: scale_exp can never be negative
-            scale_exp = diff_exp as usize;
-            if diff_exp > 21 {
-                break;
-            }
+        // Mirror coordinates to negative ray octant to simplify cell intersections
+        let mut mirror_mask = 0;
+        if ray.direction.x > 0.0 {
+            mirror_mask |= 3;
+        }
+        if ray.direction.y > 0.0 {
+            mirror_mask |= 3 << 4;
+        }
+        if ray.direction.z > 0.0 {
+            mirror_mask |= 3 << 2;
+        }
+
+        ray.origin = mirrored_pos(ray.origin, ray.direction, true);
+        Ok(Self {
+            ray,
+            hit,
+            // Clamp to prevent traversal from completely breaking for rays starting
+            // outside tree
+            pos: ray.origin.clamp(Vec3::splat(1.0), Vec3::splat(1.9999999)),
+            inv_dir: 1.0 / -ray.direction.abs(),
+            side_dist: Vec3::ZERO,
+            mirror_mask,
+            initial_ray_d: tstart.max(0.0),
+            max_dist: ray.tmax - tstart.max(0.0),
+            scale_exp: 21,
+            node_index: 0,
+            node,
+            attribute_index: node.attribute_offset,
+            gs_stack: [0; 11],
+            attribute_stack: [0; 11],
+            iteration: 0,
+        })
+    }
 
-            node_index = gs_stack[scale_exp >> 1];
-            node = tree.nodes[node_index];
-            hit.reads += 1;
+    // Returns to the ancestor at `scale_exp` at the end of a step, if it is above the
+    // current node.
+    pub(crate) fn ascend(&mut self, tree: &VoxelTree, scale_exp: usize) {
+        if scale_exp > self.scale_exp {
+            self.scale_exp = scale_exp;
+            self.node_index = self.gs_stack[scale_exp >> 1];
+            self.attribute_index = self.attribute_stack[scale_exp >> 1];
+            self.node = tree.nodes[self.node_index];
+            self.hit.reads += 1;
         }
     }
 
-    if node.is_leaf() && scale_exp <= 21 {
-        pos = mirrored_pos(pos, ray.direction, false);
-        let child_index = node_cell_index(pos, scale_exp);
+    #[inline(always)]
+    pub(crate) fn run<const ANY_HIT: bool>(self, tree: &VoxelTree) -> PackedHitInfo {
+        let Self {
+            ray,
+            mut hit,
+            mut pos,
+            inv_dir,
+            mut side_dist,
+            mirror_mask,
+            initial_ray_d,
+            max_dist,
+            mut scale_exp,
+            mut node_index,
+            mut node,
+            mut attribute_index,
+            mut gs_stack,
+            mut attribute_stack,
+            iteration,
+        } = self;
+        let nodes: &[Node] = &tree.nodes;
+
+        'traverse: {
+            for _ in iteration..MAX_ITERATIONS {
+                let mut child_index = node_cell_index(pos, scale_exp) ^ mirror_mask;
+                // Descend
+                while bit(node.mask, child_index) && !node.is_leaf() {
+                    if !node.is_loaded() {
+                        if ANY_HIT {
+                            hit.leaf_index_and_normal_and_escaped = 0;
+                            return hit;
+                        }
+                        let level = cell_level(tree, scale_exp + 2);
+                        return unloaded_hit(hit, pos, ray.direction, node, level);
+                    }
+                    gs_stack[scale_exp >> 1] = node_index;
+                    attribute_stack[scale_exp >> 1] = attribute_index;
+                    node_index = node.child_index() + popcnt(node.mask, child_index);
+
+                    if ray.cone_angle > 0.0 {
+                        // mipmap early exit check with a ray cone
+                        let t = initial_ray_d + (pos - ray.origin).length();
+                        let (diff, cell_size) = cone_excess(t, ray.cone_angle, scale_exp);
+                        // Sparse cells are descended anyway, since most rays pass through them.
+                        if diff.is_sign_positive()
+                            && nodes[node_index].coverage() >= LOD_MIN_COVERAGE
+                        {
+                            if ANY_HIT {
+                                hit.leaf_index_and_normal_and_escaped = 0;
+                                return hit;
+                            }
+                            let level = cell_level(tree, scale_exp);
+                            let child = nodes[node_index];
+                            return lod_hit(hit, pos, node, child, diff, cell_size, level);
+                        }
+                    }
+
+                    node = nodes[node_index];
+                    attribute_index += node.attribute_offset;
+                    hit.reads += 1;
+                    scale_exp -= 2;
+                    child_index = node_cell_index(pos, scale_exp) ^ mirror_mask;
+                }
 
-        let leaf_index = node.child_index() + popcnt(node.mask, child_index);
-        // hit.material_id = tree.leaves[leaf_index];
-        hit.reads += 1;
-        hit.leaf_index_and_normal_and_escaped |= (leaf_index as u32) << 4;
-        hit.leaf_index_and_normal_and_escaped &= !1;
-        // hit.leaf_index = leaf_index;
-        // hit.escaped = false;
-        hit.position = pos;
-
-        // NOTE: This is currently hard coded in the lighting code so this must be valid here.
-        assert_eq!(scale_exp, 11);
-
-        let tmax = side_dist.min_element();
-        let normal = if side_dist.x == tmax {
-            Vec3::new(-ray.direction.x.signum(), 0.0, 0.0)
-        } else if side_dist.y == tmax {
-            Vec3::new(0.0, -ray.direction.y.signum(), 0.0)
-        } else {
-            Vec3::new(0.0, 0.0, -ray.direction.z.signum())
-        };
-        let normal_id = match normal.to_array() {
-            [1.0, 0.0, 0.0] => 0,
-            [-1.0, 0.0, 0.0] => 1,
-            [0.0, 1.0, 0.0] => 2,
-            [0.0, -1.0, 0.0] => 3,
-            [0.0, 0.0, 1.0] => 4,
-            [0.0, 0.0, -1.0] => 5,
-            _ => unreachable!("{normal}"),
-        };
-        hit.leaf_index_and_normal_and_escaped |= normal_id << 1;
-        // hit.normal = normal;
+                if bit(node.mask, child_index) && node.is_leaf() {
+                    if ANY_HIT {
+                        hit.leaf_index_and_normal_and_escaped = 0;
+                        return hit;
+                    }
+                    break 'traverse;
+                }
+
+                let mut adv_scale_ecp = scale_exp;
+                // wtf
+                if ((node.mask >> (child_index & 42)) & 0x00330033) == 0 {
+                    adv_scale_ecp += 1;
+                }
+
+                // Compute next pos by intersecting with max cell sides
+                let cell_min = floor_scale(pos, adv_scale_ecp);
+
+                side_dist = (cell_min - ray.origin) * inv_dir;
+                let tmax = side_dist.min_element();
+                if tmax > max_dist {
+                    hit.miss(Outcome::MaxDistance);
+                    return hit;
+                }
+
+                let f = IVec3::splat((1 << adv_scale_ecp) - 1);
+                let t = IVec3::splat(-1);
+                let mask = side_dist.cmpeq(Vec3::splat(tmax));
+                let offset = IVec3::select(mask, t, f);
+                let neighbor_max = IVec3::new(
+                    cell_min.x.to_bits() as i32,
+                    cell_min.y.to_bits() as i32,
+                    cell_min.z.to_bits() as i32,
+                ) + offset;
+                pos = (ray.origin - ray.direction.abs() * tmax).min(Vec3::new(
+                    f32::from_bits(neighbor_max.x as u32),
+                    f32::from_bits(neighbor_max.y as u32),
+                    f32::from_bits(neighbor_max.z as u32),
+                ));
+
+                // Find common ancestor based on left-most carry bit
+                // We only care about changes in the exponent and high bits of
+                // each cell position (10'10'10'...), so the odd bits are masked.
+                let diff_pos = UVec3::new(pos.x.to_bits(), pos.y.to_bits(), pos.z.to_bits())
+                    ^ UVec3::new(
+                        cell_min.x.to_bits(),
+                        cell_min.y.to_bits(),
+                        cell_min.z.to_bits(),
+                    );
+                let combined = (diff_pos.x | diff_pos.y | diff_pos.z) & 0xFFAAAAAA;
+                let diff_exp: i32 = if combined == 0 {
+                    -1
+                } else {
+                    31 - combined.leading_zeros() as i32
+                };
+
+                if diff_exp > scale_exp as i32 {
+                    // NOTE: scale_exp can never be negative
+                    scale_exp = diff_exp as usize;
+                    if diff_exp > 21 {
+                        return hit;
+                    }
+
+                    node_index = gs_stack[scale_exp >> 1];
+                    attribute_index = attribute_stack[scale_exp >> 1];
+                    node = nodes[node_index];
+                    hit.reads += 1;
+                }
+            }
+            hit.miss(Outcome::MaxIterations);
+            return hit;
+        }
+
+        leaf_hit(
+            tree,
+            hit,
+            pos,
+            ray.direction,
+            scale_exp,
+            node,
+            attribute_index,
+            side_dist,
+        )
     }
+}
+
+// How much the ray cone at distance `t` is wider than cells at `scale_exp`, along with
+// the cell size.
+pub(crate) fn cone_excess(t: f32, cone_angle: f32, scale_exp: usize) -> (f32, f32) {
+    let cell_size = f32::from_bits((scale_exp as u32 + 127 - 23) << 23);
+    (t * cone_angle - cell_size, cell_size)
+}
+
+// Log2 of the size in voxels of cells at `scale_exp`, see `PackedHitInfo::lod_level`.
+pub(crate) fn cell_level(tree: &VoxelTree, scale_exp: usize) -> u32 {
+    scale_exp as u32 + tree.exp - 23
+}
+
+// The page beneath `node` is not loaded, so shade its average color like a mip map
+// hit until it is. `level` is the size of `node`, see `cell_level`.
+pub(crate) fn unloaded_hit(
+    mut hit: PackedHitInfo,
+    pos: Vec3,
+    direction: Vec3,
+    node: Node,
+    level: u32,
+) -> PackedHitInfo {
+    hit.position = mirrored_pos(pos, direction, false);
+    // The alpha holds the coverage of `node`, which may round to `0`.
+    hit.mip_map = node.mip_map | COARSE_ALPHA;
+    hit.leaf_index_and_normal_and_escaped = level << 4;
+    hit
+}
+
+// Blends the mip maps of `node` and its `child` by how much the ray cone outgrows
+// the child, see `cone_excess`. `level` is the size of `child`, see `cell_level`.
+pub(crate) fn lod_hit(
+    mut hit: PackedHitInfo,
+    pos: Vec3,
+    node: Node,
+    child: Node,
+    diff: f32,
+    cell_size: f32,
+    level: u32,
+) -> PackedHitInfo {
+    let linear_mip_map = VoxelTree::unpack_srgb_linear(node.mip_map);
+    let linear_child_mip_map = VoxelTree::unpack_srgb_linear(child.mip_map);
+    let linear_mip_map =
+        linear_child_mip_map.lerp(linear_mip_map, (diff / cell_size).clamp(0.0, 1.0));
+
+    hit.position = pos;
+    hit.mip_map = VoxelTree::pack_linear_rgb(linear_mip_map) | COARSE_ALPHA;
+    hit.leaf_index_and_normal_and_escaped = level << 4;
+    hit
+}
+
+// Reconstructs the hit of the leaf cell at the mirrored `pos` in `node`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn leaf_hit(
+    tree: &VoxelTree,
+    mut hit: PackedHitInfo,
+    pos: Vec3,
+    direction: Vec3,
+    scale_exp: usize,
+    node: Node,
+    attribute_index: u32,
+    side_dist: Vec3,
+) -> PackedHitInfo {
+    let pos = mirrored_pos(pos, direction, false);
+    let child_index = node_cell_index(pos, scale_exp);
+
+    let leaf_index = node.child_index() + popcnt(node.mask, child_index);
+    // hit.material_id = tree.leaves.get(leaf_index);
+    hit.reads += 1;
+    hit.leaf_index_and_normal_and_escaped |= (leaf_index as u32) << 4;
+    hit.attribute_index = attribute_index + popcnt(node.mask, child_index) as u32;
+    hit.leaf_index_and_normal_and_escaped &= !1;
+    // hit.leaf_index = leaf_index;
+    // hit.escaped = false;
+    hit.position = pos;
+
+    assert_eq!(scale_exp, tree.leaf_scale_exp());
+
+    let tmax = side_dist.min_element();
+    let normal = if side_dist.x == tmax {
+        Vec3::new(-direction.x.signum(), 0.0, 0.0)
+    } else if side_dist.y == tmax {
+        Vec3::new(0.0, -direction.y.signum(), 0.0)
+    } else {
+        Vec3::new(0.0, 0.0, -direction.z.signum())
+    };
+    let normal_id = match normal.to_array() {
+        [1.0, 0.0, 0.0] => 0,
+        [-1.0, 0.0, 0.0] => 1,
+        [0.0, 1.0, 0.0] => 2,
+        [0.0, -1.0, 0.0] => 3,
+        [0.0, 0.0, 1.0] => 4,
+        [0.0, 0.0, -1.0] => 5,
+        _ => unreachable!("{normal}"),
+    };
+    hit.leaf_index_and_normal_and_escaped |= normal_id << 1;
+    // hit.normal = normal;
     hit
 }
 
-fn bit(v: u64, i: usize) -> bool {
+pub(crate) fn bit(v: u64, i: usize) -> bool {
     ((v >> i) & 1) == 1
 }
 
@@ -266,6 +607,19 @@ fn node_cell_index(pos: Vec3, scale_exp: usize) -> usize {
     (cell.x + (cell.z * 4) + (cell.y * 16)) as usize
 }
 
+// Integer counterpart of `node_cell_index` for voxel coordinates, where `scale` is
+// the log2 size of the node's cells in voxels.
+pub(crate) fn cell_index(pos: IVec3, scale: u32) -> usize {
+    let cell = (pos >> scale as i32) & IVec3::splat(3);
+    (cell.x + (cell.z * 4) + (cell.y * 16)) as usize
+}
+
+// Inverse of `cell_index`, returning the cell offset within its node.
+pub(crate) fn cell_pos(i: usize) -> IVec3 {
+    let i = i as i32;
+    IVec3::new(i & 3, (i >> 4) & 3, (i >> 2) & 3)
+}
+
 // floor(pos / scale) * scale
 pub fn floor_scale(pos: Vec3, scale_exp: usize) -> Vec3 {
     let mask = 0xFFFFFFFF << scale_exp;
@@ -292,6 +646,6 @@ fn mirrored_pos(pos: Vec3, dir: Vec3, range_check: bool) -> Vec3 {
 }
 
 // Count number of set bits in variable range [0..i].
-fn popcnt(v: u64, i: usize) -> usize {
+pub(crate) fn popcnt(v: u64, i: usize) -> usize {
     (v & ((1 << i) - 1)).count_ones() as usize
 }
@@ -1,5 +1,8 @@
+#[cfg(not(target_arch = "wasm32"))]
+use crate::page::Pager;
 use crate::{
     camera::Camera,
+    format::LoadError,
     indirect::{DirectionalLight, SKY_COLOR},
     tree::VoxelTree,
 };
@@ -10,12 +13,32 @@ pub struct Scene {
     pub camera: Camera,
     pub tree: VoxelTree,
     pub light: DirectionalLight,
+    /// Streams `tree` in around the camera when it was opened from a paged file.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub pager: Option<Pager>,
 }
 
 impl Scene {
-    pub fn from_tree<P: AsRef<Path>>(path: P) -> Self {
-        Self {
-            tree: VoxelTree::decompress(&std::fs::read(path).unwrap()),
+    /// Loads the tree at `path` with `Pager::open` or `VoxelTree::open`.
+    ///
+    /// # Safety
+    ///
+    /// The file may be memory mapped, so it must not be truncated or modified while
+    /// the scene is alive, see `VoxelTree::open`.
+    pub unsafe fn from_tree<P: AsRef<Path>>(path: P) -> Result<Self, LoadError> {
+        // SAFETY: Forwarded to the caller.
+        #[cfg(not(target_arch = "wasm32"))]
+        let (tree, pager) = match unsafe { Pager::open(&path)? } {
+            Some((tree, pager)) => (tree, Some(pager)),
+            None => (unsafe { VoxelTree::open(path)? }, None),
+        };
+        // SAFETY: Forwarded to the caller.
+        #[cfg(target_arch = "wasm32")]
+        let tree = unsafe { VoxelTree::open(path)? };
+        Ok(Self {
+            tree,
+            #[cfg(not(target_arch = "wasm32"))]
+            pager,
             camera: Camera {
                 translation: Vec3::new(1.383996, 1.0355718, 1.1922992),
                 yaw: 9.500028,
@@ -34,12 +57,15 @@ impl Scene {
                 color: SKY_COLOR,
                 intensity: 0.05,
             },
-        }
+        })
     }
 
     pub fn castle() -> Self {
         Self {
-            tree: VoxelTree::decompress(include_bytes!("../../assets/castle.bin.bz2")),
+            tree: VoxelTree::decompress(include_bytes!("../../assets/castle.bin.bz2"))
+                .expect("castle asset is valid"),
+            #[cfg(not(target_arch = "wasm32"))]
+            pager: None,
             camera: Camera {
                 translation: Vec3::new(1.2385558, 1.0833066, 1.054556),
                 yaw: 8.175014,
@@ -61,7 +87,20 @@ impl Scene {
         }
     }
 
-    pub fn update(&mut self, dt: f32) {
+    /// Moves the camera and streams pages around it.
+    ///
+    /// If the tree was edited while paged, the pager is dropped after returning
+    /// `LoadError::StaleTree` once, and the resident pages stay as they are.
+    pub fn update(&mut self, dt: f32) -> Result<(), LoadError> {
         self.camera.update(&self.tree, dt);
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(pager) = &mut self.pager {
+            let result = pager.update(&mut self.tree, self.camera.translation);
+            if let Err(LoadError::StaleTree) = result {
+                self.pager = None;
+            }
+            result?;
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,151 @@
+// Backing memory for the arrays of a `VoxelTree`.
+//
+// Trees written with `Compression::Mapped` are opened without copying: `nodes` and
+// `leaves` point straight into the memory mapped file, so pages are loaded lazily and
+// shared between processes. Anything that needs to mutate the arrays, such as editing,
+// copies them into an owned `Vec` first.
+
+use crate::tree::Node;
+use std::ops::{Deref, DerefMut};
+
+/// An array that is either owned or borrowed from a memory mapped file.
+pub struct Storage<T>(Repr<T>);
+
+enum Repr<T> {
+    Owned(Vec<T>),
+    #[cfg(not(target_arch = "wasm32"))]
+    Mapped {
+        map: std::sync::Arc<memmap2::Mmap>,
+        offset: usize,
+        len: usize,
+        _marker: std::marker::PhantomData<T>,
+    },
+}
+
+/// Types that can be read in place from a mapped file.
+///
+/// # Safety
+///
+/// Every bit pattern must be a valid value, and the in memory layout must match the
+/// little endian layout written by `format::encode`.
+pub(crate) unsafe trait Plain: Copy {}
+
+unsafe impl Plain for u8 {}
+unsafe impl Plain for u16 {}
+// The padding after `child_index_is_leaf` is never read as a value.
+unsafe impl Plain for Node {}
+
+impl<T> Storage<T> {
+    /// Borrows `len` elements starting at byte `offset` of `map`.
+    ///
+    /// Panics if the range is out of bounds or misaligned.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn mapped(map: std::sync::Arc<memmap2::Mmap>, offset: usize, len: usize) -> Self
+    where
+        T: Plain,
+    {
+        assert!(offset + len * size_of::<T>() <= map.len());
+        assert!((map.as_ptr() as usize + offset).is_multiple_of(align_of::<T>()));
+        Self(Repr::Mapped {
+            map,
+            offset,
+            len,
+            _marker: std::marker::PhantomData,
+        })
+    }
+
+    /// Whether the array is read from a mapped file.
+    pub fn is_mapped(&self) -> bool {
+        !matches!(self.0, Repr::Owned(_))
+    }
+
+    /// Returns the owned array, copying it out of the mapped file first if needed.
+    pub fn to_mut(&mut self) -> &mut Vec<T>
+    where
+        T: Clone,
+    {
+        if self.is_mapped() {
+            *self = Self(Repr::Owned(self.to_vec()));
+        }
+        match &mut self.0 {
+            Repr::Owned(vec) => vec,
+            #[cfg(not(target_arch = "wasm32"))]
+            Repr::Mapped { .. } => unreachable!(),
+        }
+    }
+}
+
+impl<T> Deref for Storage<T> {
+    type Target = [T];
+
+    #[inline]
+    fn deref(&self) -> &[T] {
+        match &self.0 {
+            Repr::Owned(vec) => vec,
+            #[cfg(not(target_arch = "wasm32"))]
+            Repr::Mapped {
+                map, offset, len, ..
+            } => {
+                // SAFETY: `Storage::mapped` checked bounds and alignment and requires
+                // `T: Plain`. The map is kept alive and never mutated.
+                unsafe { std::slice::from_raw_parts(map.as_ptr().add(*offset).cast(), *len) }
+            }
+        }
+    }
+}
+
+impl<T: Clone> DerefMut for Storage<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.to_mut()
+    }
+}
+
+impl<T> Default for Storage<T> {
+    fn default() -> Self {
+        Self(Repr::Owned(Vec::new()))
+    }
+}
+
+impl<T> From<Vec<T>> for Storage<T> {
+    fn from(vec: Vec<T>) -> Self {
+        Self(Repr::Owned(vec))
+    }
+}
+
+impl<T: Clone> Clone for Storage<T> {
+    fn clone(&self) -> Self {
+        match &self.0 {
+            Repr::Owned(vec) => Self(Repr::Owned(vec.clone())),
+            #[cfg(not(target_arch = "wasm32"))]
+            Repr::Mapped {
+                map, offset, len, ..
+            } => Self(Repr::Mapped {
+                map: map.clone(),
+                offset: *offset,
+                len: *len,
+                _marker: std::marker::PhantomData,
+            }),
+        }
+    }
+}
+
+impl<T: PartialEq> PartialEq for Storage<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: Eq> Eq for Storage<T> {}
+
+// Serialized like a `Vec`, so postcard payloads are unaffected by where the arrays live.
+impl<T: serde::Serialize> serde::Serialize for Storage<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for Storage<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::deserialize(deserializer).map(Self::from)
+    }
+}
@@ -0,0 +1,295 @@
+// Geometric transforms of `VoxelTree` and `VoxelMap`.
+//
+// Rotations by 90° and mirroring permute the 64 cells of every node the same way on
+// every level, so trees are transformed structurally: each shared subtree is rebuilt
+// once and the result is compacted to share identical subtrees again. Translations
+// don't align with node boundaries and go through a `VoxelMap` instead.
+
+use crate::map::{Brick, VoxelMap};
+use crate::ray::{bit, cell_pos, popcnt};
+use crate::tree::{Leaves, MAX_EXP, Node, NodeHash, VoxelTree, par_generate_tree};
+use fxhash::FxHashMap;
+use glam::IVec3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+impl Axis {
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    // The other two axes in right handed order, so a quarter turn takes the first
+    // onto the second.
+    fn plane(self) -> (usize, usize) {
+        match self {
+            Self::X => (1, 2),
+            Self::Y => (2, 0),
+            Self::Z => (0, 1),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Orientation {
+    Mirror(Axis),
+    Rotate(Axis, u32),
+}
+
+impl Orientation {
+    // Maps the voxel at `pos` within a cube of `size` voxels starting at zero. A size
+    // of `0` mirrors and rotates around the origin instead.
+    fn apply(self, mut pos: IVec3, size: i32) -> IVec3 {
+        match self {
+            Self::Mirror(axis) => pos[axis.index()] = size - 1 - pos[axis.index()],
+            Self::Rotate(axis, quarter_turns) => {
+                let (a, b) = axis.plane();
+                for _ in 0..quarter_turns {
+                    (pos[a], pos[b]) = (size - 1 - pos[b], pos[a]);
+                }
+            }
+        }
+        pos
+    }
+}
+
+impl VoxelTree {
+    /// Returns the tree moved by `offset` voxels.
+    ///
+    /// Voxels moved below zero are dropped and the depth grows to fit the rest.
+    /// Unloaded pages are dropped.
+    ///
+    /// Panics if the result needs a depth above `MAX_EXP`.
+    pub fn translated(&self, offset: IVec3) -> Self {
+        let mut map = self.to_map();
+        map.translate(offset);
+        map.crop(IVec3::ZERO, IVec3::MAX);
+        let exp = self.exp.max(map.tree_exp());
+        assert!(exp <= MAX_EXP, "translated tree needs a depth of 2^{exp}");
+        let mut nodes = vec![Node::default()];
+        let mut leaves = Leaves::for_materials(self.materials.len());
+        nodes[0] = par_generate_tree(
+            &map,
+            &mut NodeHash::default(),
+            &mut nodes,
+            &mut leaves,
+            exp,
+            IVec3::ZERO,
+            &mut 0,
+        );
+        Self::new(nodes.into(), leaves, self.materials.clone(), exp)
+    }
+
+    /// Returns the tree rotated around its center by `quarter_turns` times 90°,
+    /// counterclockwise when looking down `axis`.
+    ///
+    /// Unloaded pages are dropped.
+    pub fn rotated(&self, axis: Axis, quarter_turns: i32) -> Self {
+        self.oriented(Orientation::Rotate(
+            axis,
+            quarter_turns.rem_euclid(4) as u32,
+        ))
+    }
+
+    /// Returns the tree mirrored along `axis`.
+    ///
+    /// Unloaded pages are dropped.
+    pub fn mirrored(&self, axis: Axis) -> Self {
+        self.oriented(Orientation::Mirror(axis))
+    }
+
+    /// Returns the tree with every voxel outside of `min..max` removed.
+    ///
+    /// Voxels keep their positions, `VoxelTree::translated` moves the region to the
+    /// origin.
+    pub fn cropped(&self, min: IVec3, max: IVec3) -> Self {
+        let mut tree = Self::new(
+            self.nodes.clone(),
+            self.leaves.clone(),
+            self.materials.clone(),
+            self.exp,
+        );
+        let size = IVec3::splat(1 << self.exp);
+        let min = min.clamp(IVec3::ZERO, size);
+        let max = max.clamp(min, size);
+        let root = self.nodes[0];
+        tree.nodes[0] = tree.crop_node(root, self.exp, IVec3::ZERO, min, max);
+        tree.compact();
+        tree
+    }
+
+    // Removes the voxels outside of `min..max` beneath `node`, which spans `2^scale`
+    // voxels from `pos`. Only nodes crossing the boundary are rebuilt.
+    fn crop_node(&mut self, node: Node, scale: u32, pos: IVec3, min: IVec3, max: IVec3) -> Node {
+        let node_max = pos + IVec3::splat(1 << scale);
+        if pos.cmpge(max).any() || node_max.cmple(min).any() {
+            return Node::default();
+        }
+        if node.mask == 0
+            || !node.is_loaded()
+            || (pos.cmpge(min).all() && node_max.cmple(max).all())
+        {
+            return node;
+        }
+        if scale == 2 {
+            // Cells are indexed by `x + z*4 + y*16`
+            let mut cells = [0; 64];
+            for (i, cell) in cells.iter_mut().enumerate() {
+                let voxel_pos = pos + cell_pos(i);
+                if bit(node.mask, i) && voxel_pos.cmpge(min).all() && voxel_pos.cmplt(max).all() {
+                    *cell = self.leaves.get(node.child_index() + popcnt(node.mask, i));
+                }
+            }
+            return self.push_leaf_node(&cells);
+        }
+
+        let scale = scale - 2;
+        let mut children = [Node::default(); 64];
+        for (i, child) in children.iter_mut().enumerate() {
+            if bit(node.mask, i) {
+                let original = self.nodes[node.child_index() + popcnt(node.mask, i)];
+                *child = self.crop_node(original, scale, pos + (cell_pos(i) << scale), min, max);
+            }
+        }
+        self.push_interior_node(&children)
+    }
+
+    fn oriented(&self, orientation: Orientation) -> Self {
+        let mut oriented = Oriented {
+            tree: self,
+            orientation,
+            remapped: FxHashMap::default(),
+            oriented: Self::new(
+                vec![Node::default()].into(),
+                Leaves::for_materials(self.materials.len()),
+                self.materials.clone(),
+                self.exp,
+            ),
+        };
+        let root = oriented.node(self.nodes[0]);
+        let mut tree = oriented.oriented;
+        tree.nodes[0] = root;
+        // Leaf groups that only match after reordering are shared again.
+        tree.compact();
+        tree
+    }
+}
+
+struct Oriented<'a> {
+    tree: &'a VoxelTree,
+    orientation: Orientation,
+    // Maps the mask and `child_index_is_leaf` of the source tree to the transformed
+    // node. Leaf groups and child arrays are shared regardless of the mask, so the
+    // index alone doesn't determine the layout.
+    remapped: FxHashMap<(u64, u32), Node>,
+    oriented: VoxelTree,
+}
+
+impl Oriented<'_> {
+    fn node(&mut self, node: Node) -> Node {
+        if node.mask == 0 || !node.is_loaded() {
+            return Node::default();
+        }
+        if let Some(&remapped) = self.remapped.get(&(node.mask, node.child_index_is_leaf)) {
+            return remapped;
+        }
+
+        let tree = self.tree;
+        let oriented = if node.is_leaf() {
+            // Cells are indexed by `x + z*4 + y*16`
+            let mut cells = [0; 64];
+            for i in 0..64 {
+                if bit(node.mask, i) {
+                    let material = tree.leaves.get(node.child_index() + popcnt(node.mask, i));
+                    cells[self.cell_index(i)] = material;
+                }
+            }
+            self.oriented.push_leaf_node(&cells)
+        } else {
+            let mut children = [Node::default(); 64];
+            for i in 0..64 {
+                if bit(node.mask, i) {
+                    let child = tree.nodes[node.child_index() + popcnt(node.mask, i)];
+                    children[self.cell_index(i)] = self.node(child);
+                }
+            }
+            self.oriented.push_interior_node(&children)
+        };
+        self.remapped
+            .insert((node.mask, node.child_index_is_leaf), oriented);
+        oriented
+    }
+
+    fn cell_index(&self, i: usize) -> usize {
+        let pos = self.orientation.apply(cell_pos(i), 4);
+        (pos.x + pos.z * 4 + pos.y * 16) as usize
+    }
+}
+
+impl VoxelMap {
+    /// Moves every voxel by `offset`.
+    pub fn translate(&mut self, offset: IVec3) {
+        if offset % 8 == IVec3::ZERO {
+            let bricks = offset / 8;
+            self.chunks = self.chunks.drain().map(|(k, v)| (k + bricks, v)).collect();
+        } else {
+            self.remap(|pos| pos + offset);
+        }
+    }
+
+    /// Rotates the map around the origin by `quarter_turns` times 90°,
+    /// counterclockwise when looking down `axis`.
+    ///
+    /// The result usually has negative coordinates, see `VoxelMap::shift_to_positive`.
+    pub fn rotate(&mut self, axis: Axis, quarter_turns: i32) {
+        let orientation = Orientation::Rotate(axis, quarter_turns.rem_euclid(4) as u32);
+        self.remap(|pos| orientation.apply(pos, 0));
+    }
+
+    /// Mirrors the map along `axis` around the origin.
+    ///
+    /// The result has negative coordinates, see `VoxelMap::shift_to_positive`.
+    pub fn mirror(&mut self, axis: Axis) {
+        let orientation = Orientation::Mirror(axis);
+        self.remap(|pos| orientation.apply(pos, 0));
+    }
+
+    /// Removes every voxel outside of `min..max`.
+    pub fn crop(&mut self, min: IVec3, max: IVec3) {
+        self.chunks.retain(|&brick_pos, brick| {
+            let brick_min = brick_pos * 8;
+            if brick_min.cmpge(min).all() && (brick_min + IVec3::splat(8)).cmple(max).all() {
+                return true;
+            }
+            for i in 0..512 {
+                let pos = brick_min + IVec3::new(i & 7, i >> 6, (i >> 3) & 7);
+                if pos.cmplt(min).any() || pos.cmpge(max).any() {
+                    brick.set(i as usize, 0);
+                }
+            }
+            !brick.is_empty()
+        });
+    }
+
+    // Moves every solid voxel to `f(pos)`, which must not map two voxels to the same
+    // position.
+    fn remap(&mut self, f: impl Fn(IVec3) -> IVec3) {
+        let mut chunks = FxHashMap::<IVec3, Brick>::default();
+        for (brick_pos, brick) in &self.chunks {
+            for (i, material) in brick.iter().enumerate() {
+                if material == 0 {
+                    continue;
+                }
+                let i = i as i32;
+                let pos = f(brick_pos * 8 + IVec3::new(i & 7, i >> 6, (i >> 3) & 7));
+                let brick = chunks.entry(pos >> 3).or_default();
+                brick.set(Brick::voxel_index(pos & 7), material);
+            }
+        }
+        self.chunks = chunks;
+    }
+}
@@ -1,34 +1,116 @@
+use crate::format::{self, Compression, LoadError, SourceMetadata};
 use crate::map::{Brick, VoxelMap};
-use fxhash::FxHashMap;
+use crate::material::Material;
+use crate::storage::Storage;
+use fxhash::{FxHashMap, FxHashSet};
 use glam::{IVec3, Vec3};
-use std::io::{Read, Write};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::sync::atomic::{AtomicU64, Ordering};
 use tint::Color;
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct VoxelTree {
-    pub nodes: Vec<Node>,
-    pub leaves: Vec<u8>,
-    pub palette: Vec<u32>,
+    pub nodes: Storage<Node>,
+    pub leaves: Leaves,
+    /// Indexed by the values in `leaves`, `0` is reserved for empty cells.
+    pub materials: Vec<Material>,
+    /// The tree spans `2^exp` voxels along each axis.
+    ///
+    /// Must be even and within `MIN_EXP..=MAX_EXP`.
     pub exp: u32,
+    /// Unique to this tree and replaced by every edit, so `page::Pager` can tell
+    /// that its node indices went stale.
+    #[serde(skip, default = "next_generation")]
+    pub(crate) generation: u64,
 }
 
+pub(crate) fn next_generation() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Index into `VoxelTree::materials`, `0` is empty.
+pub type MaterialId = u16;
+
+pub const MIN_EXP: u32 = 2;
+// Leaf cells at bit 1 are the smallest the f32 traversal can represent.
+pub const MAX_EXP: u32 = 22;
+
 impl VoxelTree {
-    pub fn compress(&self) -> Vec<u8> {
-        let bytes = postcard::to_allocvec(self).unwrap();
-        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::best());
-        encoder.write_all(&bytes).unwrap();
-        encoder.finish().unwrap()
+    pub fn new(nodes: Storage<Node>, leaves: Leaves, materials: Vec<Material>, exp: u32) -> Self {
+        Self {
+            nodes,
+            leaves,
+            materials,
+            exp,
+            generation: next_generation(),
+        }
+    }
+
+    pub fn compress(&self, source: SourceMetadata) -> Vec<u8> {
+        format::encode(self, Compression::Bzip2, source)
+    }
+
+    pub fn decompress(bytes: &[u8]) -> Result<Self, LoadError> {
+        format::decode(bytes)
+    }
+
+    /// Loads the tree at `path`.
+    ///
+    /// Files written with `Compression::Mapped` are memory mapped and traversed in
+    /// place, other files are read and decoded like `VoxelTree::decompress`.
+    /// Either way the tree is checked with `VoxelTree::validate`, which reads every
+    /// reachable node once.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be truncated or modified, by this or any other process,
+    /// while the tree or any clone of it is alive. Nodes are read from the mapping
+    /// during traversal, so a changed file is undefined behavior, e.g. a `SIGBUS` or
+    /// torn reads.
+    pub unsafe fn open(path: impl AsRef<std::path::Path>) -> Result<Self, LoadError> {
+        // SAFETY: Forwarded to the caller.
+        unsafe { format::open(path) }
+    }
+
+    /// Number of levels between the root and the leaf voxels.
+    pub fn depth(&self) -> u32 {
+        self.exp / 2
+    }
+
+    /// Exponent bit of a leaf voxel's cell in the [1, 2) tree space, see `ray::floor_scale`.
+    ///
+    /// The root cells start at bit 21 and every level descends 2 bits.
+    pub fn leaf_scale_exp(&self) -> usize {
+        23 - self.exp as usize
+    }
+
+    /// Edge length of a single voxel in the [1, 2) tree space.
+    pub fn voxel_size(&self) -> f32 {
+        1.0 / (1u32 << self.exp) as f32
+    }
+
+    /// Number of voxels in the tree, which is also the number of distinct attribute
+    /// indices.
+    pub fn voxel_count(&self) -> u64 {
+        subtree_voxels(&self.nodes, self.nodes[0]) as u64
     }
 
-    pub fn decompress(bytes: &[u8]) -> Self {
-        let mut encoder = bzip2::read::BzDecoder::new(bytes);
-        let mut decompressed = Vec::with_capacity(bytes.len());
-        encoder.read_to_end(&mut decompressed).unwrap();
-        postcard::from_bytes(&decompressed).unwrap()
+    /// Expands the tree back into bricks.
+    pub fn to_map(&self) -> VoxelMap {
+        let mut map = VoxelMap::default();
+        let len = map.materials.len().max(self.materials.len());
+        map.materials.resize(len, Material::default());
+        map.materials[..self.materials.len()].copy_from_slice(&self.materials);
+        for (pos, material) in self.voxels() {
+            let brick = map.chunks.entry(pos >> 3).or_default();
+            brick.set(Brick::voxel_index(pos & 7), material);
+        }
+        map
     }
 
     pub fn packed_srgb(&self, material_id: usize) -> u32 {
-        self.palette[material_id]
+        self.materials[material_id].albedo
     }
 
     pub fn pack_srgb(srgb: tint::Srgb) -> u32 {
@@ -64,39 +146,543 @@ impl VoxelTree {
         let linear = self.srgb(material_id).to_linear();
         Vec3::new(linear.r(), linear.g(), linear.b())
     }
+
+    /// Appends the non-empty `cells`, indexed by `x + z*4 + y*16`, to `leaves` and
+    /// returns the leaf node pointing at them, or an empty node if every cell is empty.
+    pub(crate) fn push_leaf_node(&mut self, cells: &[MaterialId; 64]) -> Node {
+        let mut mask = 0u64;
+        let mut active_leaves = Vec::with_capacity(64);
+        for (i, &data) in cells.iter().enumerate() {
+            if data != 0 {
+                mask |= 1 << i;
+                active_leaves.push(data);
+            }
+        }
+        if mask == 0 {
+            return Node::default();
+        }
+        let leaf_index = self.leaves.len() as u32;
+        self.leaves.extend_from_slice(&active_leaves);
+        Node {
+            mask,
+            child_index_is_leaf: (leaf_index << 1) | 1,
+            mip_map: leaf_mip_map(&self.materials, mask, &active_leaves),
+            attribute_offset: 0,
+        }
+    }
+
+    /// Appends the non-empty `children`, indexed by `x + z*4 + y*16`, to `nodes` and
+    /// returns the interior node pointing at them, or an empty node if every child is
+    /// empty. The arrays beneath `children` must already be in `nodes`.
+    pub(crate) fn push_interior_node(&mut self, children: &[Node; 64]) -> Node {
+        let mut mask = 0u64;
+        let mut active_children = Vec::with_capacity(64);
+        for (i, child) in children.iter().enumerate() {
+            if child.mask != 0 {
+                mask |= 1 << i;
+                active_children.push(*child);
+            }
+        }
+        if mask == 0 {
+            return Node::default();
+        }
+        assign_sibling_offsets(&self.nodes, &mut active_children);
+        let child_index = self.nodes.len() as u32;
+        self.nodes.to_mut().extend_from_slice(&active_children);
+        Node {
+            mask,
+            child_index_is_leaf: child_index << 1,
+            mip_map: interior_mip_map(&self.nodes, mask, &active_children),
+            attribute_offset: 0,
+        }
+    }
 }
 
 #[repr(C)]
-#[derive(Default, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[derive(Default, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
 pub struct Node {
     // [     31      |    1    ]
     // [ child_index | is_leaf ]
     // is_leaf     // Indicates if this node is a leaf containing plain voxels.
     // child_index // Absolute offset to array of existing child nodes/voxels.
-    child_index_is_leaf: u32,
+    pub(crate) child_index_is_leaf: u32,
     pub mask: u64,
+    /// Packed sRGB average of the voxels beneath this node, weighted by voxel count,
+    /// with `Node::coverage` in the alpha byte.
     pub mip_map: u32,
+    /// Number of voxels contained in the preceding siblings of this node.
+    ///
+    /// Child arrays and leaf groups are shared between identical subtrees, so
+    /// neither node nor leaf indices identify a single voxel. Summing the offsets
+    /// along the path from the root yields a unique, dense attribute index for every
+    /// voxel instead, which per voxel data such as lighting is keyed on.
+    pub attribute_offset: u32,
 }
 
 impl Node {
+    /// `child_index_is_leaf` of a node whose children are in a page that is not
+    /// loaded, see `page::Pager`.
+    pub(crate) const UNLOADED: u32 = u32::MAX - 1;
+
     pub fn is_leaf(&self) -> bool {
         (self.child_index_is_leaf & 1) == 1
     }
 
+    /// Whether the children of this node are resident, only `page::Pager` unloads
+    /// them.
+    pub fn is_loaded(&self) -> bool {
+        self.child_index_is_leaf != Self::UNLOADED
+    }
+
     pub fn child_index(&self) -> usize {
         (self.child_index_is_leaf >> 1) as usize
     }
+
+    /// Estimated fraction of rays along the most covered axis that hit a voxel within
+    /// this node, so surfaces are near `1` regardless of orientation and scattered
+    /// voxels are near `0`.
+    ///
+    /// Trees written before coverage was stored report `1`.
+    pub fn coverage(&self) -> f32 {
+        (self.mip_map >> 24) as f32 / 255.0
+    }
+}
+
+/// Counts the voxels beneath `node` by following the last child of every level.
+///
+/// Unloaded subtrees are counted as empty.
+pub fn subtree_voxels(nodes: &[Node], mut node: Node) -> u32 {
+    let mut voxels = 0;
+    while !node.is_leaf() {
+        if node.mask == 0 || !node.is_loaded() {
+            return voxels;
+        }
+        node = nodes[node.child_index() + node.mask.count_ones() as usize - 1];
+        voxels += node.attribute_offset;
+    }
+    voxels + node.mask.count_ones()
+}
+
+/// Material ids of the leaf voxels.
+///
+/// Ids are stored in a single byte unless the material table has more than 256
+/// entries, which is recorded in `format::Header::wide_leaves`.
+#[derive(Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Leaves {
+    Narrow(Storage<u8>),
+    Wide(Storage<u16>),
+}
+
+impl Default for Leaves {
+    fn default() -> Self {
+        Self::Narrow(Storage::default())
+    }
+}
+
+impl Leaves {
+    /// Returns empty leaves wide enough to index `materials` entries.
+    pub fn for_materials(materials: usize) -> Self {
+        if materials > 256 {
+            Self::Wide(Storage::default())
+        } else {
+            Self::Narrow(Storage::default())
+        }
+    }
+
+    pub fn is_wide(&self) -> bool {
+        matches!(self, Self::Wide(_))
+    }
+
+    /// Size of a single id in bytes.
+    pub fn id_size(&self) -> usize {
+        match self {
+            Self::Narrow(_) => size_of::<u8>(),
+            Self::Wide(_) => size_of::<u16>(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Narrow(leaves) => leaves.len(),
+            Self::Wide(leaves) => leaves.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    pub fn get(&self, index: usize) -> MaterialId {
+        match self {
+            Self::Narrow(leaves) => leaves[index] as MaterialId,
+            Self::Wide(leaves) => leaves[index],
+        }
+    }
+
+    pub fn to_vec(&self, range: std::ops::Range<usize>) -> Vec<MaterialId> {
+        match self {
+            Self::Narrow(leaves) => leaves[range].iter().map(|&id| id as MaterialId).collect(),
+            Self::Wide(leaves) => leaves[range].to_vec(),
+        }
+    }
+
+    /// Appends `ids`, switching to wide storage if any of them doesn't fit in a byte.
+    pub fn extend_from_slice(&mut self, ids: &[MaterialId]) {
+        if let Self::Narrow(leaves) = self
+            && ids.iter().any(|&id| id > u8::MAX as MaterialId)
+        {
+            let wide = leaves
+                .iter()
+                .map(|&id| id as MaterialId)
+                .collect::<Vec<_>>();
+            *self = Self::Wide(wide.into());
+        }
+        match self {
+            Self::Narrow(leaves) => leaves.to_mut().extend(ids.iter().map(|&id| id as u8)),
+            Self::Wide(leaves) => leaves.to_mut().extend_from_slice(ids),
+        }
+    }
+}
+
+/// Shares identical leaf groups and child arrays while generating a tree, turning it
+/// into a sparse voxel DAG.
+#[derive(Default, Clone)]
+pub struct NodeHash {
+    leaves: FxHashMap<Vec<MaterialId>, u32>,
+    children: FxHashMap<Vec<Node>, u32>,
+}
+
+impl NodeHash {
+    /// Returns the index of `active_leaves` in `leaves`, appending them if they are
+    /// not already present.
+    pub(crate) fn leaves(
+        &mut self,
+        active_leaves: Vec<MaterialId>,
+        leaves: &mut Leaves,
+        saved_bytes: &mut usize,
+    ) -> u32 {
+        if let Some(&existing_index) = self.leaves.get(&active_leaves) {
+            *saved_bytes += active_leaves.len() * leaves.id_size();
+            existing_index
+        } else {
+            let new_index = leaves.len() as u32;
+            leaves.extend_from_slice(&active_leaves);
+            self.leaves.insert(active_leaves, new_index);
+            new_index
+        }
+    }
+
+    /// Returns the index of `children` in `nodes`, appending them if they are not
+    /// already present.
+    pub(crate) fn children(
+        &mut self,
+        children: &[Node],
+        nodes: &mut Vec<Node>,
+        saved_bytes: &mut usize,
+    ) -> u32 {
+        if let Some(&existing_index) = self.children.get(children) {
+            *saved_bytes += size_of_val(children);
+            existing_index
+        } else {
+            let new_index = nodes.len() as u32;
+            nodes.extend_from_slice(children);
+            self.children.insert(children.to_vec(), new_index);
+            new_index
+        }
+    }
+}
+
+/// Assigns `Node::attribute_offset` for a compacted child array.
+pub(crate) fn assign_sibling_offsets(nodes: &[Node], children: &mut [Node]) {
+    let mut voxels = 0;
+    for child in children.iter_mut() {
+        child.attribute_offset = voxels;
+        voxels += subtree_voxels(nodes, *child);
+    }
+}
+
+pub(crate) fn leaf_mip_map(materials: &[Material], mask: u64, active_leaves: &[MaterialId]) -> u32 {
+    if active_leaves.is_empty() {
+        return 0;
+    }
+    let linear_mip_map = active_leaves.iter().fold(Vec3::ZERO, |c, &data| {
+        c + materials[data as usize].linear_albedo()
+    });
+    let mut cells = [0.0; 64];
+    for (i, cell) in cells.iter_mut().enumerate() {
+        if (mask >> i) & 1 == 1 {
+            *cell = 1.0;
+        }
+    }
+    pack_mip_map(
+        linear_mip_map / active_leaves.len() as f32,
+        projected_coverage(&cells),
+    )
+}
+
+/// `nodes` must contain the arrays beneath `children`, which are weighted by the
+/// number of voxels they contain.
+pub(crate) fn interior_mip_map(nodes: &[Node], mask: u64, children: &[Node]) -> u32 {
+    let mut accumulated_mip_map = Vec3::ZERO;
+    let mut voxels = 0.0;
+    let mut cells = [0.0; 64];
+    let mut child_iter = children.iter();
+    for (i, cell) in cells.iter_mut().enumerate() {
+        if (mask >> i) & 1 == 0 {
+            continue;
+        }
+        let child = child_iter.next().unwrap();
+        // Unloaded pages count as a single voxel.
+        let weight = subtree_voxels(nodes, *child).max(1) as f32;
+        accumulated_mip_map += VoxelTree::unpack_srgb_linear(child.mip_map) * weight;
+        voxels += weight;
+        *cell = child.coverage();
+    }
+    pack_mip_map(accumulated_mip_map / voxels, projected_coverage(&cells))
+}
+
+fn pack_mip_map(linear: Vec3, coverage: f32) -> u32 {
+    (VoxelTree::pack_linear_rgb(linear) & 0x00FF_FFFF) | (((coverage * 255.0).round() as u32) << 24)
+}
+
+// Combines the coverage of the 64 cells of a node, indexed by `x + z*4 + y*16`.
+//
+// Along each of the 16 rows per axis, cells are treated as independent layers that
+// let `1 - coverage` of the rays through. The rows are averaged per axis and the most
+// covered axis is returned.
+fn projected_coverage(cells: &[f32; 64]) -> f32 {
+    let mut coverage = Vec3::ZERO;
+    for a in 0..4 {
+        for b in 0..4 {
+            let mut transmittance = Vec3::ONE;
+            for c in 0..4 {
+                transmittance *= 1.0
+                    - Vec3::new(
+                        cells[c + a * 4 + b * 16],
+                        cells[a + b * 4 + c * 16],
+                        cells[a + c * 4 + b * 16],
+                    );
+            }
+            coverage += 1.0 - transmittance;
+        }
+    }
+    coverage.max_element() / 16.0
 }
 
 pub fn generate_tree(
     map: &VoxelMap,
-    _node_hash: &mut FxHashMap<Vec<u8>, u32>,
+    node_hash: &mut NodeHash,
+    nodes: &mut Vec<Node>,
+    leaves: &mut Leaves,
+    scale: u32,
+    pos: IVec3,
+    saved_bytes: &mut usize,
+) -> Node {
+    generate_node(map, node_hash, nodes, leaves, scale, pos, saved_bytes, None)
+}
+
+/// Generates the same tree as `generate_tree`, building subtrees in parallel.
+///
+/// Subtrees two levels below `scale` are generated independently into their own
+/// arrays and then replayed into `nodes` and `leaves` in the order `generate_tree`
+/// visits them, so the output and `saved_bytes` match the serial version exactly.
+pub fn par_generate_tree(
+    map: &VoxelMap,
+    node_hash: &mut NodeHash,
     nodes: &mut Vec<Node>,
-    leaves: &mut Vec<u8>,
+    leaves: &mut Leaves,
+    scale: u32,
+    pos: IVec3,
+    saved_bytes: &mut usize,
+) -> Node {
+    // Subtrees are located through brick positions, so they must span whole bricks.
+    if scale < 8 {
+        return generate_tree(map, node_hash, nodes, leaves, scale, pos, saved_bytes);
+    }
+
+    // The serial path is the reference for the replayed output.
+    #[cfg(debug_assertions)]
+    let serial = (
+        node_hash.clone(),
+        nodes.clone(),
+        leaves.clone(),
+        *saved_bytes,
+    );
+
+    let task_scale = scale - 4;
+    let tasks = map
+        .chunks
+        .keys()
+        .map(|&brick_pos| (brick_pos * 8 - pos) >> task_scale)
+        .filter(|cell| cell.cmpge(IVec3::ZERO).all() && cell.cmplt(IVec3::splat(16)).all())
+        .map(|cell| pos + (cell << task_scale))
+        .collect::<FxHashSet<_>>();
+    let subtrees = Subtrees {
+        scale: task_scale,
+        subtrees: tasks
+            .into_par_iter()
+            .map(|task_pos| {
+                let mut nodes = Vec::new();
+                let mut leaves = Leaves::for_materials(map.materials.len());
+                let root = generate_tree(
+                    map,
+                    &mut NodeHash::default(),
+                    &mut nodes,
+                    &mut leaves,
+                    task_scale,
+                    task_pos,
+                    &mut 0,
+                );
+                (
+                    task_pos,
+                    Subtree {
+                        root,
+                        nodes,
+                        leaves,
+                    },
+                )
+            })
+            .collect(),
+    };
+    let root = generate_node(
+        map,
+        node_hash,
+        nodes,
+        leaves,
+        scale,
+        pos,
+        saved_bytes,
+        Some(&subtrees),
+    );
+
+    #[cfg(debug_assertions)]
+    {
+        let (mut node_hash, mut serial_nodes, mut serial_leaves, mut serial_saved_bytes) = serial;
+        let serial_root = generate_tree(
+            map,
+            &mut node_hash,
+            &mut serial_nodes,
+            &mut serial_leaves,
+            scale,
+            pos,
+            &mut serial_saved_bytes,
+        );
+        debug_assert!(
+            root == serial_root
+                && *nodes == serial_nodes
+                && *leaves == serial_leaves
+                && *saved_bytes == serial_saved_bytes,
+            "the parallel tree differs from the serial one"
+        );
+    }
+    root
+}
+
+// Subtrees generated by `par_generate_tree`, keyed by position.
+struct Subtrees {
+    scale: u32,
+    subtrees: FxHashMap<IVec3, Subtree>,
+}
+
+struct Subtree {
+    root: Node,
+    nodes: Vec<Node>,
+    leaves: Leaves,
+}
+
+// Copies a `Subtree` into the shared arrays, calling `NodeHash` in the same order as
+// `generate_tree`.
+struct Replay<'a> {
+    subtree: &'a Subtree,
+    // Maps `child_index_is_leaf` within the subtree to the shared arrays, along with
+    // the bytes of every array beneath it.
+    remapped: FxHashMap<u32, (u32, usize)>,
+}
+
+impl Replay<'_> {
+    fn node(
+        &mut self,
+        node: Node,
+        node_hash: &mut NodeHash,
+        nodes: &mut Vec<Node>,
+        leaves: &mut Leaves,
+        saved_bytes: &mut usize,
+    ) -> (Node, usize) {
+        if let Some(&(child_index_is_leaf, bytes)) = self.remapped.get(&node.child_index_is_leaf) {
+            // `generate_tree` would generate the repeated subtree again and find every
+            // one of its arrays in `node_hash`.
+            *saved_bytes += bytes;
+            return (
+                Node {
+                    child_index_is_leaf,
+                    ..node
+                },
+                bytes,
+            );
+        }
+
+        let subtree = self.subtree;
+        let start = node.child_index();
+        let end = start + node.mask.count_ones() as usize;
+        let (child_index_is_leaf, bytes) = if node.is_leaf() {
+            let active_leaves = subtree.leaves.to_vec(start..end);
+            let leaf_index = node_hash.leaves(active_leaves, leaves, saved_bytes);
+            ((leaf_index << 1) | 1, (end - start) * leaves.id_size())
+        } else {
+            let mut bytes = size_of_val(&subtree.nodes[start..end]);
+            let children = subtree.nodes[start..end]
+                .iter()
+                .map(|child| {
+                    let (child, child_bytes) =
+                        self.node(*child, node_hash, nodes, leaves, saved_bytes);
+                    bytes += child_bytes;
+                    child
+                })
+                .collect::<Vec<_>>();
+            let child_index = node_hash.children(&children, nodes, saved_bytes);
+            (child_index << 1, bytes)
+        };
+        self.remapped
+            .insert(node.child_index_is_leaf, (child_index_is_leaf, bytes));
+        (
+            Node {
+                child_index_is_leaf,
+                ..node
+            },
+            bytes,
+        )
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn generate_node(
+    map: &VoxelMap,
+    node_hash: &mut NodeHash,
+    nodes: &mut Vec<Node>,
+    leaves: &mut Leaves,
     mut scale: u32,
     pos: IVec3,
-    _saved_bytes: &mut usize,
+    saved_bytes: &mut usize,
+    subtrees: Option<&Subtrees>,
 ) -> Node {
+    if let Some(subtrees) = subtrees
+        && scale == subtrees.scale
+    {
+        return match subtrees.subtrees.get(&pos) {
+            Some(subtree) if subtree.root.mask != 0 => {
+                let mut replay = Replay {
+                    subtree,
+                    remapped: FxHashMap::default(),
+                };
+                replay
+                    .node(subtree.root, node_hash, nodes, leaves, saved_bytes)
+                    .0
+            }
+            _ => Node::default(),
+        };
+    }
+
     debug_assert!(
         scale.is_multiple_of(2),
         "the tree is descended in increments of 2"
@@ -109,47 +695,31 @@ pub fn generate_tree(
             Some(brick) => {
                 // Repack voxels into 4x4x4 tile
                 // Cells are indexed by `x + z*4 + y*16`
-                let mut temp = [0u8; 64];
+                let mut temp = [0; 64];
                 for i in (0..64).step_by(4) {
                     let offset = Brick::voxel_index(
                         IVec3::new(pos.x, pos.y + ((i >> 4) & 3), pos.z + ((i >> 2) & 3)) & 7,
                     );
-                    temp[i as usize..i as usize + 4]
-                        .copy_from_slice(&brick.data[offset..offset + 4]);
+                    for x in 0..4 {
+                        temp[i as usize + x] = brick.get(offset + x);
+                    }
                 }
                 let mut mask = 0u64;
                 let mut active_leaves = Vec::with_capacity(64);
-                let mut linear_mip_map = Vec3::ZERO;
                 // generate bitmask of `temp[i] != 0`.
                 for (i, &data) in temp.iter().enumerate() {
                     if data != 0 {
                         mask |= 1 << i;
                         active_leaves.push(data);
-                        linear_mip_map += VoxelTree::unpack_srgb_linear(map.palette[data as usize]);
                     }
                 }
-                let leaf_index = leaves.len() as u32;
-                leaves.extend_from_slice(&active_leaves);
-                // TODO: This saves 10 MB on the castle scene! It just causes leaf_id
-                // in the shaders to not be unique...
-                // let leaf_index = if let Some(&existing_index) = node_hash.get(&active_leaves) {
-                //     *saved_bytes += size_of_val(active_leaves.as_slice());
-                //     existing_index
-                // } else {
-                //     let new_index = leaves.len() as u32;
-                //     leaves.extend_from_slice(&active_leaves);
-                //     node_hash.insert(active_leaves, new_index);
-                //     new_index
-                // };
-                let mip_map = if !active_leaves.is_empty() {
-                    VoxelTree::pack_linear_rgb(linear_mip_map / active_leaves.len() as f32)
-                } else {
-                    0
-                };
+                let mip_map = leaf_mip_map(&map.materials, mask, &active_leaves);
+                let leaf_index = node_hash.leaves(active_leaves, leaves, saved_bytes);
                 Node {
                     mask,
                     child_index_is_leaf: (leaf_index << 1) | 1,
                     mip_map,
+                    attribute_offset: 0,
                 }
             }
             None => Node::default(),
@@ -167,14 +737,15 @@ pub fn generate_tree(
         let mut mask = 0u64;
         for i in 0..64 {
             let child_pos = IVec3::new(i & 3, (i >> 4) & 3, (i >> 2) & 3);
-            let child = generate_tree(
+            let child = generate_node(
                 map,
-                _node_hash,
+                node_hash,
                 nodes,
                 leaves,
                 scale,
                 pos + (child_pos << scale),
-                _saved_bytes,
+                saved_bytes,
+                subtrees,
             );
             // Node contains voxel/children data
             if child.mask != 0 {
@@ -183,18 +754,15 @@ pub fn generate_tree(
                 children_len += 1
             }
         }
-        let len = nodes.len() as u32;
-        nodes.extend(&children[..children_len]);
-        let accumulated_mip_map = children[..children_len].iter().fold(Vec3::ZERO, |c, l| {
-            let linear = VoxelTree::unpack_srgb(l.mip_map).to_linear();
-            c + Vec3::new(linear.r(), linear.g(), linear.b())
-        });
-        let linear_mip_map = accumulated_mip_map / children_len as f32;
-        let mip_map = VoxelTree::pack_linear_rgb(linear_mip_map);
+        let children = &mut children[..children_len];
+        assign_sibling_offsets(nodes, children);
+        let child_index = node_hash.children(children, nodes, saved_bytes);
+        let mip_map = interior_mip_map(nodes, mask, children);
         Node {
             mask,
-            child_index_is_leaf: len << 1,
+            child_index_is_leaf: child_index << 1,
             mip_map,
+            attribute_offset: 0,
         }
     }
 }
@@ -0,0 +1,276 @@
+// Integrity checks and statistics for a `VoxelTree`.
+
+use crate::tree::{MAX_EXP, MIN_EXP, MaterialId, Node, VoxelTree};
+use fxhash::{FxHashMap, FxHashSet};
+
+/// A broken invariant found by `VoxelTree::validate`.
+///
+/// `node` is the index into `VoxelTree::nodes` of the offending node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The tree depth is odd or outside of `MIN_EXP..=MAX_EXP`.
+    UnsupportedExp(u32),
+    /// `nodes` is empty.
+    MissingRoot,
+    /// The child range of an interior node extends past `nodes`.
+    ChildrenOutOfBounds { node: usize },
+    /// The leaf range of a leaf node extends past `leaves`.
+    LeavesOutOfBounds { node: usize },
+    /// A stored child has an empty mask, so the parent's mask has more bits set than
+    /// it has children.
+    EmptyChild { node: usize },
+    /// A set bit in a leaf node's mask points at material `0`.
+    EmptyLeaf { node: usize },
+    /// A leaf points past the end of `materials`.
+    MaterialOutOfRange { node: usize, material: MaterialId },
+    /// A node is one of its own descendants.
+    Cycle { node: usize },
+    /// A leaf node above the last level, or an interior node on it.
+    InconsistentDepth { node: usize, depth: u32 },
+    /// `attribute_offset` is not the number of voxels in the preceding siblings.
+    AttributeOffset { node: usize },
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnsupportedExp(exp) => write!(
+                f,
+                "tree depth 2^{exp} is not an even exponent in {MIN_EXP}..={MAX_EXP}"
+            ),
+            Self::MissingRoot => write!(f, "tree has no root node"),
+            Self::ChildrenOutOfBounds { node } => {
+                write!(f, "children of node {node} are out of bounds")
+            }
+            Self::LeavesOutOfBounds { node } => {
+                write!(f, "leaves of node {node} are out of bounds")
+            }
+            Self::EmptyChild { node } => write!(f, "node {node} is stored with an empty mask"),
+            Self::EmptyLeaf { node } => write!(f, "node {node} has an empty leaf"),
+            Self::MaterialOutOfRange { node, material } => write!(
+                f,
+                "node {node} references material {material} outside of the material table"
+            ),
+            Self::Cycle { node } => write!(f, "node {node} is its own descendant"),
+            Self::InconsistentDepth { node, depth } => {
+                write!(
+                    f,
+                    "node {node} at depth {depth} does not match the tree depth"
+                )
+            }
+            Self::AttributeOffset { node } => {
+                write!(f, "node {node} has an incorrect attribute offset")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+#[derive(Debug, Default, Clone)]
+pub struct LevelStats {
+    /// Distinct nodes on this level, shared subtrees are counted once.
+    pub nodes: usize,
+    /// Set bits across the masks of those nodes.
+    pub children: u64,
+}
+
+impl LevelStats {
+    /// Fraction of the 64 cells per node that are occupied.
+    pub fn fill_ratio(&self) -> f64 {
+        if self.nodes == 0 {
+            return 0.0;
+        }
+        self.children as f64 / (self.nodes * 64) as f64
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct TreeStats {
+    /// Indexed by depth, the root is level 0 and the last level holds the leaf nodes.
+    pub levels: Vec<LevelStats>,
+    pub voxels: u64,
+    /// Bytes of `nodes` reachable from the root.
+    pub node_bytes: usize,
+    /// Bytes of `leaves` reachable from the root.
+    pub leaf_bytes: usize,
+    pub material_bytes: usize,
+    /// Bytes left behind by edits until `VoxelTree::compact` is called.
+    pub unreachable_bytes: usize,
+}
+
+impl VoxelTree {
+    /// Checks that every node reachable from the root is well formed.
+    ///
+    /// Unloaded pages are skipped, along with the attribute offsets of their later
+    /// siblings.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if !self.exp.is_multiple_of(2) || !(MIN_EXP..=MAX_EXP).contains(&self.exp) {
+            return Err(ValidationError::UnsupportedExp(self.exp));
+        }
+        let Some(&root) = self.nodes.first() else {
+            return Err(ValidationError::MissingRoot);
+        };
+        if root.mask == 0 {
+            return Ok(());
+        }
+        let mut validator = Validator {
+            tree: self,
+            visited: FxHashMap::default(),
+        };
+        validator.node(0, root, 0).map(|_| ())
+    }
+
+    /// Gathers per level node counts and a memory breakdown.
+    ///
+    /// Assumes the tree passes `VoxelTree::validate`.
+    pub fn stats(&self) -> TreeStats {
+        let root = self.nodes[0];
+        let mut levels = vec![LevelStats::default(); self.depth() as usize];
+        levels[0] = LevelStats {
+            nodes: 1,
+            children: root.mask.count_ones() as u64,
+        };
+
+        let mut visited = FxHashSet::default();
+        let mut stack = vec![(root, 0)];
+        // Shared arrays may be reached through different masks, covering overlapping
+        // ranges, so the ranges are merged before they are counted.
+        let mut node_ranges = Vec::new();
+        let mut leaf_ranges = Vec::new();
+        while let Some((node, depth)) = stack.pop() {
+            if node.mask == 0
+                || !node.is_loaded()
+                || !visited.insert((node.mask, node.child_index_is_leaf))
+            {
+                continue;
+            }
+            let start = node.child_index();
+            let end = start + node.mask.count_ones() as usize;
+            if node.is_leaf() {
+                leaf_ranges.push(start..end);
+                continue;
+            }
+            node_ranges.push(start..end);
+            for child in &self.nodes[start..end] {
+                let level = &mut levels[depth + 1];
+                level.nodes += 1;
+                level.children += child.mask.count_ones() as u64;
+                stack.push((*child, depth + 1));
+            }
+        }
+
+        // The root is stored on its own at index 0.
+        let reachable_nodes = 1 + covered_len(node_ranges);
+        let reachable_leaves = covered_len(leaf_ranges);
+        let node_size = std::mem::size_of::<Node>();
+        TreeStats {
+            levels,
+            voxels: self.voxel_count(),
+            node_bytes: reachable_nodes * node_size,
+            leaf_bytes: reachable_leaves * self.leaves.id_size(),
+            material_bytes: std::mem::size_of_val(self.materials.as_slice()),
+            unreachable_bytes: (self.nodes.len() - reachable_nodes) * node_size
+                + (self.leaves.len() - reachable_leaves) * self.leaves.id_size(),
+        }
+    }
+}
+
+// Returns the number of indices covered by the union of `ranges`.
+fn covered_len(mut ranges: Vec<std::ops::Range<usize>>) -> usize {
+    ranges.sort_unstable_by_key(|range| range.start);
+    let mut len = 0;
+    let mut end = 0;
+    for range in ranges {
+        len += range.end.saturating_sub(range.start.max(end));
+        end = end.max(range.end);
+    }
+    len
+}
+
+enum Visit {
+    InProgress,
+    Done { depth: u32, voxels: Option<u64> },
+}
+
+struct Validator<'a> {
+    tree: &'a VoxelTree,
+    // Keyed by mask and `child_index_is_leaf`, so shared subtrees are only checked
+    // once. The index alone doesn't determine the range, as leaf groups and child
+    // arrays are shared regardless of the mask.
+    visited: FxHashMap<(u64, u32), Visit>,
+}
+
+impl Validator<'_> {
+    // Returns the number of voxels beneath `node`, which is stored at `nodes[index]`,
+    // or `None` if part of it is unloaded.
+    fn node(
+        &mut self,
+        index: usize,
+        node: Node,
+        depth: u32,
+    ) -> Result<Option<u64>, ValidationError> {
+        if node.mask == 0 {
+            return Err(ValidationError::EmptyChild { node: index });
+        }
+        if !node.is_loaded() {
+            return Ok(None);
+        }
+        let key = (node.mask, node.child_index_is_leaf);
+        match self.visited.get(&key) {
+            Some(Visit::InProgress) => return Err(ValidationError::Cycle { node: index }),
+            Some(&Visit::Done {
+                depth: visited_depth,
+                voxels,
+            }) => {
+                if visited_depth != depth {
+                    return Err(ValidationError::InconsistentDepth { node: index, depth });
+                }
+                return Ok(voxels);
+            }
+            None => {}
+        }
+        if node.is_leaf() != (depth == self.tree.depth() - 1) {
+            return Err(ValidationError::InconsistentDepth { node: index, depth });
+        }
+
+        let start = node.child_index();
+        let end = start + node.mask.count_ones() as usize;
+        let voxels = if node.is_leaf() {
+            if end > self.tree.leaves.len() {
+                return Err(ValidationError::LeavesOutOfBounds { node: index });
+            }
+            for material in self.tree.leaves.to_vec(start..end) {
+                if material == 0 {
+                    return Err(ValidationError::EmptyLeaf { node: index });
+                }
+                if material as usize >= self.tree.materials.len() {
+                    return Err(ValidationError::MaterialOutOfRange {
+                        node: index,
+                        material,
+                    });
+                }
+            }
+            Some((end - start) as u64)
+        } else {
+            if end > self.tree.nodes.len() {
+                return Err(ValidationError::ChildrenOutOfBounds { node: index });
+            }
+            self.visited.insert(key, Visit::InProgress);
+            let mut voxels = Some(0);
+            for child_index in start..end {
+                let child = self.tree.nodes[child_index];
+                if voxels.is_some_and(|voxels| child.attribute_offset as u64 != voxels) {
+                    return Err(ValidationError::AttributeOffset { node: child_index });
+                }
+                let child_voxels = self.node(child_index, child, depth + 1)?;
+                voxels = voxels
+                    .zip(child_voxels)
+                    .map(|(voxels, child)| voxels + child);
+            }
+            voxels
+        };
+        self.visited.insert(key, Visit::Done { depth, voxels });
+        Ok(voxels)
+    }
+}
@@ -0,0 +1,196 @@
+//! Renders a single frame of a `.bin.bz2` tree without opening a window. Paged trees
+//! load the pages around the camera first.
+//!
+//! ```text
+//! headless <map.bin.bz2> <output.png|output.ppm> [options]
+//!     --width <px>
+//!     --height <px>
+//!     --translation <x,y,z>
+//!     --yaw <radians>
+//!     --pitch <radians>
+//!     --fov <degrees>
+//!     --lod-bias <levels>
+//! ```
+
+use glam::Vec3;
+use rube::{
+    indirect::{self, IndirectPass},
+    march::{self, MarchPass},
+    scene::Scene,
+};
+use std::{io::Write, path::Path, process::ExitCode};
+
+const USAGE: &str = "usage: headless <map.bin.bz2> <output.png|output.ppm> [--width <px>] \
+[--height <px>] [--translation <x,y,z>] [--yaw <radians>] [--pitch <radians>] [--fov <degrees>] \
+[--lod-bias <levels>]";
+
+struct Args {
+    map: String,
+    output: String,
+    width: usize,
+    height: usize,
+    translation: Option<Vec3>,
+    yaw: Option<f32>,
+    pitch: Option<f32>,
+    fov: Option<f32>,
+    lod_bias: Option<f32>,
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("[ERROR] {err}\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // SAFETY: The map is not modified while the frame renders.
+    let mut scene = match unsafe { Scene::from_tree(&args.map) } {
+        Ok(scene) => scene,
+        Err(err) => {
+            eprintln!("[ERROR] failed to load {}: {err}", args.map);
+            return ExitCode::FAILURE;
+        }
+    };
+    if let Some(translation) = args.translation {
+        scene.camera.translation = translation;
+    }
+    if let Some(yaw) = args.yaw {
+        scene.camera.yaw = yaw;
+    }
+    if let Some(pitch) = args.pitch {
+        scene.camera.pitch = pitch;
+    }
+    if let Some(fov) = args.fov {
+        scene.camera.fov = fov.to_radians();
+    }
+    if let Some(lod_bias) = args.lod_bias {
+        scene.camera.lod_bias = lod_bias;
+    }
+    if let Some(pager) = &mut scene.pager
+        && let Err(err) = pager.load_near(&mut scene.tree, scene.camera.translation)
+    {
+        eprintln!("[ERROR] failed to load pages of {}: {err}", args.map);
+        return ExitCode::FAILURE;
+    }
+
+    let start = std::time::Instant::now();
+    let mut march_pass = MarchPass::new(args.width, args.height);
+    let mut indirect_pass = IndirectPass::new(args.width, args.height);
+    let mut pixels = vec![0u32; args.width * args.height];
+    march::march_pass(&scene, &mut march_pass, args.width, args.height);
+    indirect::indirect_pass(&scene, &march_pass, &mut indirect_pass, &mut pixels);
+    println!(
+        "Rendered {}x{} in {:?}",
+        args.width,
+        args.height,
+        start.elapsed()
+    );
+
+    if let Err(err) = write_image(&args.output, &pixels, args.width, args.height) {
+        eprintln!("[ERROR] failed to write {}: {err}", args.output);
+        return ExitCode::FAILURE;
+    }
+    println!("Wrote {}", args.output);
+    ExitCode::SUCCESS
+}
+
+fn parse_args(mut iter: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut positional = Vec::new();
+    let mut args = Args {
+        map: String::new(),
+        output: String::new(),
+        width: 16 * 30,
+        height: 9 * 30,
+        translation: None,
+        yaw: None,
+        pitch: None,
+        fov: None,
+        lod_bias: None,
+    };
+
+    while let Some(arg) = iter.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            positional.push(arg);
+            continue;
+        };
+        let value = iter
+            .next()
+            .ok_or_else(|| format!("missing value for `--{flag}`"))?;
+        match flag {
+            "width" => args.width = parse(flag, &value)?,
+            "height" => args.height = parse(flag, &value)?,
+            "translation" => {
+                let components = value
+                    .split(',')
+                    .map(|c| parse::<f32>(flag, c.trim()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let [x, y, z] = components[..] else {
+                    return Err(format!("expected `x,y,z` for `--{flag}`, got `{value}`"));
+                };
+                args.translation = Some(Vec3::new(x, y, z));
+            }
+            "yaw" => args.yaw = Some(parse(flag, &value)?),
+            "pitch" => args.pitch = Some(parse(flag, &value)?),
+            "fov" => args.fov = Some(parse(flag, &value)?),
+            "lod-bias" => args.lod_bias = Some(parse(flag, &value)?),
+            _ => return Err(format!("unknown option `--{flag}`")),
+        }
+    }
+
+    if args.width == 0 || args.height == 0 {
+        return Err("resolution must be non-zero".to_string());
+    }
+    let mut positional = positional.into_iter();
+    args.map = positional.next().ok_or("missing map path")?;
+    args.output = positional.next().ok_or("missing output path")?;
+    if let Some(extra) = positional.next() {
+        return Err(format!("unexpected argument `{extra}`"));
+    }
+    Ok(args)
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value `{value}` for `--{flag}`"))
+}
+
+/// Writes `pixels` in the platform format (`0x00RRGGBB`) to `path`, choosing the
+/// encoding from the file extension.
+fn write_image(
+    path: impl AsRef<Path>,
+    pixels: &[u32],
+    width: usize,
+    height: usize,
+) -> std::io::Result<()> {
+    let rgb = pixels
+        .iter()
+        .flat_map(|p| [(p >> 16) as u8, (p >> 8) as u8, *p as u8])
+        .collect::<Vec<_>>();
+
+    let path = path.as_ref();
+    let png = match path.extension().map(|e| e.to_string_lossy()).as_deref() {
+        Some("ppm") => false,
+        Some("png") => true,
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "expected a `.png` or `.ppm` extension",
+            ));
+        }
+    };
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    if png {
+        let mut encoder = png::Encoder::new(&mut file, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&rgb)?;
+    } else {
+        write!(file, "P6\n{width} {height}\n255\n")?;
+        file.write_all(&rgb)?;
+    }
+    file.flush()
+}
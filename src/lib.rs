@@ -4,14 +4,23 @@ use wasm_bindgen::prelude::*;
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen(start))]
 pub fn run() {
     let scale = 30;
+    #[cfg(not(target_arch = "wasm32"))]
+    let path = std::env::args().nth(1).expect("map path provided");
+    #[cfg(target_arch = "wasm32")]
+    let path = "http://127.0.0.1:1334/assets/sponza.bin.bz2";
+    // SAFETY: Assets are not modified while the app is running.
+    let create_world = match unsafe { rube::create_world_from_tree(path.clone()) } {
+        Ok(create_world) => create_world,
+        Err(err) => {
+            eprintln!("[ERROR] failed to load {path}: {err}");
+            return;
+        }
+    };
     rube_platform::run(
         16 * scale,
         9 * scale,
         5,
-        #[cfg(not(target_arch = "wasm32"))]
-        rube::create_world_from_tree(std::env::args().nth(1).expect("map path provided")),
-        #[cfg(target_arch = "wasm32")]
-        rube::create_world_from_tree("http://127.0.0.1:1334/assets/sponza.bin.bz2"),
+        create_world,
         rube::handle_input,
         rube::update_and_render,
     );